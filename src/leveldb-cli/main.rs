@@ -0,0 +1,896 @@
+#[crate_id = "leveldb-cli"];
+
+#[comment = "Inspect and modify LevelDB databases from the command line."];
+#[license = "MIT"];
+#[crate_type = "bin"];
+
+extern mod extra;
+extern mod leveldb;
+
+use std::io::{Reader, Writer, io_error};
+use std::io::fs::File;
+use std::os;
+
+use extra::base64::{FromBase64, ToBase64, STANDARD};
+use extra::getopts::{Matches, getopts, optflag, optopt};
+use extra::hex::{FromHex, ToHex};
+
+use leveldb::{DB, DBIterator, WriteBatchVisitor};
+use leveldb::diff::{diff, OnlyInA, OnlyInB, Changed};
+use leveldb::keys::prefix_successor;
+use leveldb::dump::{DumpFormat, Binary, JsonLines, HexCsv, Base64Csv};
+use leveldb::options::{CREATE_IF_MISSING, OpenOption};
+use leveldb::log::{LogReader, Corrupt, Torn, decode_batch};
+use leveldb::manifest::{FileMetaData, Version, VersionEdit};
+use leveldb::table::{Table, TypeDeletion, TypeValue};
+
+mod shell;
+
+type error = ~str;
+
+static USAGE: &'static str = "usage: leveldb-cli <command> <db> [args] [options]
+
+commands:
+    get <db> <key>
+    put <db> <key> <value>
+    delete <db> <key>
+    scan <db> [--prefix P] [--start S] [--end E] [--limit N] [--reverse]
+    count <db> [--prefix P] [--start S] [--end E]
+    dump <db> [--format F] [--out FILE] [--start S --end E]
+    load <db> [--format F] [--in FILE]
+    compact <db> [--start S] [--end E]
+    verify <db> [--start S --end E]  read everything, checking checksums
+    diff <db> <other-db>             list keys added, removed or changed
+    repair <db>
+    destroy <db>
+    stats <db>
+    sizes <db> [<start> <end>]...
+    shell <db>                       interactive shell; type help inside
+    sst <file>                       dump a table file without opening the db
+    wal <file>                       dump the batches of a .log file
+    manifest <db|file> [--edits]     show the table files of each level
+
+options:
+    --key-format utf8|hex|base64     how keys are read and printed
+    --value-format utf8|hex|base64   how values are read and printed
+    --format binary|json|hex-csv|base64-csv   dump format
+    --create                         create the database if missing";
+
+// Leveldb statistics printed by `stats`, besides the per-level file counts.
+static PROPERTIES: &'static [&'static str] = &["leveldb.stats", "leveldb.sstables"];
+static NUM_LEVELS: uint = 7;
+
+#[deriving(Eq, Clone)]
+enum Encoding {
+    Utf8,
+    Hex,
+    Base64
+}
+
+fn parse_encoding(name: Option<~str>) -> Result<Encoding, error> {
+    match name {
+        None => Ok(Utf8),
+        Some(name) => match name.as_slice() {
+            "utf8" => Ok(Utf8),
+            "hex" => Ok(Hex),
+            "base64" => Ok(Base64),
+            _ => Err(format!("unknown encoding {}", name))
+        }
+    }
+}
+
+fn parse_dump_format(name: Option<~str>) -> Result<DumpFormat, error> {
+    match name {
+        None => Ok(Binary),
+        Some(name) => match name.as_slice() {
+            "binary" => Ok(Binary),
+            "json" => Ok(JsonLines),
+            "hex-csv" => Ok(HexCsv),
+            "base64-csv" => Ok(Base64Csv),
+            _ => Err(format!("unknown dump format {}", name))
+        }
+    }
+}
+
+fn decode(encoding: Encoding, s: &str) -> Result<~[u8], error> {
+    match encoding {
+        Utf8 => unescape(s),
+        Hex => s.from_hex(),
+        Base64 => s.from_base64()
+    }
+}
+
+// UTF-8 output prints valid UTF-8 as it is, escaping control characters
+// and backslashes; other bytes are escaped, so binary keys stay readable.
+// `unescape` reads the escapes back, so printed keys can be passed to
+// `get`.
+fn encode(encoding: Encoding, bytes: &[u8]) -> ~str {
+    match encoding {
+        Utf8 => {
+            let mut s = ~"";
+            match std::str::from_utf8_opt(bytes) {
+                Some(text) => for c in text.chars() {
+                    if c == '\\' {
+                        s.push_str("\\\\");
+                    } else if c < ' ' || c == '\x7f' {
+                        s.push_str(format!("\\\\x{:02x}", c as uint));
+                    } else {
+                        s.push_char(c);
+                    }
+                },
+                None => for &b in bytes.iter() {
+                    if b == ('\\' as u8) {
+                        s.push_str("\\\\");
+                    } else if b >= 0x20 && b < 0x7f {
+                        s.push_char(b as char);
+                    } else {
+                        s.push_str(format!("\\\\x{:02x}", b));
+                    }
+                }
+            }
+            s
+        },
+        Hex => bytes.to_hex(),
+        Base64 => bytes.to_base64(STANDARD)
+    }
+}
+
+// Read `\xHH` as the byte HH and `\\` as a backslash.
+fn unescape(s: &str) -> Result<~[u8], error> {
+    let bytes = s.as_bytes();
+    let mut out = ~[];
+    let mut i = 0u;
+    while i < bytes.len() {
+        if bytes[i] != ('\\' as u8) {
+            out.push(bytes[i]);
+            i += 1;
+        } else if i + 1 < bytes.len() && bytes[i + 1] == ('\\' as u8) {
+            out.push(bytes[i]);
+            i += 2;
+        } else if i + 3 < bytes.len() && bytes[i + 1] == ('x' as u8)
+                && bytes[i + 2] < 0x80 && bytes[i + 3] < 0x80 {
+            match s.slice(i + 2, i + 4).from_hex() {
+                Ok(b) => out.push_all(b),
+                Err(_) => return Err(format!("bad escape in {}", s))
+            }
+            i += 4;
+        } else {
+            return Err(format!("bad escape in {}", s));
+        }
+    }
+    Ok(out)
+}
+
+struct Command {
+    name: ~str,
+    path: ~str,
+    args: ~[~str],
+    matches: Matches,
+    keys: Encoding,
+    values: Encoding
+}
+
+impl Command {
+    fn arg<'a>(&'a self, i: uint) -> Result<&'a str, error> {
+        if i < self.args.len() {
+            Ok(self.args[i].as_slice())
+        } else {
+            Err(format!("{}: missing argument\n{}", self.name, USAGE))
+        }
+    }
+
+    fn key_arg(&self, i: uint) -> Result<~[u8], error> {
+        match self.arg(i) {
+            Ok(s) => decode(self.keys, s),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn key_opt(&self, name: &str) -> Result<Option<~[u8]>, error> {
+        match self.matches.opt_str(name) {
+            Some(s) => decode(self.keys, s).map(|key| Some(key)),
+            None => Ok(None)
+        }
+    }
+
+    fn open(&self) -> Result<~DB, error> {
+        let options: &[OpenOption] = if self.matches.opt_present("create") {
+            &[CREATE_IF_MISSING]
+        } else {
+            &[]
+        };
+        DB::open(self.path, options)
+    }
+}
+
+// The bounds selected by --prefix, --start and --end.
+struct Bounds {
+    start: Option<~[u8]>,
+    end: Option<~[u8]>,
+    prefix: Option<~[u8]>
+}
+
+impl Bounds {
+    fn from_command(cmd: &Command) -> Result<Bounds, error> {
+        let start = match cmd.key_opt("start") {
+            Ok(key) => key,
+            Err(err) => return Err(err)
+        };
+        let end = match cmd.key_opt("end") {
+            Ok(key) => key,
+            Err(err) => return Err(err)
+        };
+        let prefix = match cmd.key_opt("prefix") {
+            Ok(key) => key,
+            Err(err) => return Err(err)
+        };
+        Ok(Bounds {
+            start: start,
+            end: end,
+            prefix: prefix
+        })
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        match self.start {
+            Some(ref start) if key < start.as_slice() => return false,
+            _ => {}
+        }
+        match self.end {
+            Some(ref end) if key >= end.as_slice() => return false,
+            _ => {}
+        }
+        match self.prefix {
+            Some(ref prefix) => key.starts_with(*prefix),
+            None => true
+        }
+    }
+
+    // Whether no key at or past `key` in the scan direction can match.
+    fn is_past(&self, key: &[u8], reverse: bool) -> bool {
+        if reverse {
+            match self.start {
+                Some(ref start) if key < start.as_slice() => return true,
+                _ => {}
+            }
+            match self.prefix {
+                Some(ref prefix) => key < prefix.as_slice(),
+                None => false
+            }
+        } else {
+            match self.end {
+                Some(ref end) if key >= end.as_slice() => return true,
+                _ => {}
+            }
+            match self.prefix {
+                Some(ref prefix) => key > prefix.as_slice() && !key.starts_with(*prefix),
+                None => false
+            }
+        }
+    }
+
+    // Position `it` on the first key to visit.
+    fn seek(&self, it: &mut DBIterator, reverse: bool) {
+        if reverse {
+            // Start below the end or past the prefix, whichever is lower,
+            // rather than walking back from the last key.
+            let limit = match self.prefix {
+                Some(ref prefix) => prefix_successor(*prefix),
+                None => None
+            };
+            let limit = match (limit, &self.end) {
+                (Some(limit), &Some(ref end)) if limit > *end => Some(end.clone()),
+                (None, &Some(ref end)) => Some(end.clone()),
+                (limit, _) => limit
+            };
+            match limit {
+                Some(limit) => {
+                    it.seek(limit);
+                    if it.is_valid() {
+                        it.prev();
+                    } else {
+                        it.seek_to_last();
+                    }
+                },
+                None => it.seek_to_last()
+            }
+        } else {
+            match (&self.start, &self.prefix) {
+                (&Some(ref start), &Some(ref prefix)) => {
+                    it.seek(if start > prefix { *start } else { *prefix })
+                },
+                (&Some(ref start), &None) => it.seek(*start),
+                (&None, &Some(ref prefix)) => it.seek(*prefix),
+                (&None, &None) => it.seek_to_first()
+            }
+        }
+    }
+
+    // Call `f` on each matching entry until it returns false.
+    fn scan(&self, db: &DB, reverse: bool, f: |&[u8], &[u8]| -> bool) -> Result<(), error> {
+        let mut it = db.iter([]);
+        self.seek(&mut it, reverse);
+        while it.is_valid() {
+            let key = it.key();
+            if self.is_past(key, reverse) {
+                break;
+            }
+            if self.contains(key) && !f(key, it.value()) {
+                break;
+            }
+            if reverse {
+                it.prev();
+            } else {
+                it.next();
+            }
+        }
+        match it.get_error() {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+}
+
+fn get(cmd: &Command, db: &DB) -> Result<(), error> {
+    let key = match cmd.key_arg(0) {
+        Ok(key) => key,
+        Err(err) => return Err(err)
+    };
+    match db.get_opt(key, []) {
+        Ok(Some(value)) => {
+            println!("{}", encode(cmd.values, value));
+            Ok(())
+        },
+        Ok(None) => Err(~"not found"),
+        Err(err) => Err(err)
+    }
+}
+
+fn put(cmd: &Command, db: &DB) -> Result<(), error> {
+    let key = match cmd.key_arg(0) {
+        Ok(key) => key,
+        Err(err) => return Err(err)
+    };
+    let value = match cmd.arg(1) {
+        Ok(s) => match decode(cmd.values, s) {
+            Ok(value) => value,
+            Err(err) => return Err(err)
+        },
+        Err(err) => return Err(err)
+    };
+    db.put(key, value, [])
+}
+
+fn delete(cmd: &Command, db: &DB) -> Result<(), error> {
+    match cmd.key_arg(0) {
+        Ok(key) => db.delete(key, []),
+        Err(err) => Err(err)
+    }
+}
+
+fn scan(cmd: &Command, db: &DB) -> Result<(), error> {
+    let bounds = match Bounds::from_command(cmd) {
+        Ok(bounds) => bounds,
+        Err(err) => return Err(err)
+    };
+    let limit = match cmd.matches.opt_str("limit") {
+        Some(s) => match from_str::<uint>(s) {
+            Some(n) => Some(n),
+            None => return Err(format!("bad limit {}", s))
+        },
+        None => None
+    };
+    if limit == Some(0) {
+        return Ok(());
+    }
+    let mut printed = 0u;
+    bounds.scan(db, cmd.matches.opt_present("reverse"), |key, value| {
+        println!("{} => {}", encode(cmd.keys, key), encode(cmd.values, value));
+        printed += 1;
+        match limit {
+            Some(limit) => printed < limit,
+            None => true
+        }
+    })
+}
+
+fn count(cmd: &Command, db: &DB) -> Result<(), error> {
+    let bounds = match Bounds::from_command(cmd) {
+        Ok(bounds) => bounds,
+        Err(err) => return Err(err)
+    };
+    let mut n = 0u64;
+    match bounds.scan(db, false, |_, _| { n += 1; true }) {
+        Ok(_) => {
+            println!("{}", n);
+            Ok(())
+        },
+        Err(err) => Err(err)
+    }
+}
+
+// Open `path` for reading, or create it for writing, with I/O errors
+// returned rather than raised.
+fn open_file(path: &str, create: bool) -> Result<File, error> {
+    let mut err = None;
+    let file = io_error::cond.trap(|e| err = Some(e.desc.to_owned())).inside(|| {
+        if create {
+            File::create(&Path::new(path))
+        } else {
+            File::open(&Path::new(path))
+        }
+    });
+    match (file, err) {
+        (_, Some(err)) => Err(format!("{}: {}", path, err)),
+        (Some(file), None) => Ok(file),
+        (None, None) => Err(format!("{}: cannot open", path))
+    }
+}
+
+fn dump(cmd: &Command, db: &DB) -> Result<(), error> {
+    let format = match parse_dump_format(cmd.matches.opt_str("format")) {
+        Ok(format) => format,
+        Err(err) => return Err(err)
+    };
+    let (start, end) = match (cmd.key_opt("start"), cmd.key_opt("end")) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(err), _) | (_, Err(err)) => return Err(err)
+    };
+    let range = match (&start, &end) {
+        (&Some(ref start), &Some(ref end)) => Some((start.as_slice(), end.as_slice())),
+        (&None, &None) => None,
+        _ => return Err(~"dump: --start and --end go together")
+    };
+    let res = match cmd.matches.opt_str("out") {
+        Some(path) => match open_file(path, true) {
+            Ok(mut file) => db.dump(&mut file as &mut Writer, format, range),
+            Err(err) => return Err(err)
+        },
+        None => db.dump(&mut std::io::stdout() as &mut Writer, format, range)
+    };
+    match res {
+        Ok(count) => {
+            writeln!(&mut std::io::stderr(), "dumped {} entries", count);
+            Ok(())
+        },
+        Err(err) => Err(err)
+    }
+}
+
+fn load(cmd: &Command, db: &DB) -> Result<(), error> {
+    let format = match parse_dump_format(cmd.matches.opt_str("format")) {
+        Ok(format) => format,
+        Err(err) => return Err(err)
+    };
+    let res = match cmd.matches.opt_str("in") {
+        Some(path) => match open_file(path, false) {
+            Ok(mut file) => db.load(&mut file as &mut Reader, format),
+            Err(err) => return Err(err)
+        },
+        None => db.load(&mut std::io::stdin() as &mut Reader, format)
+    };
+    match res {
+        Ok(count) => {
+            writeln!(&mut std::io::stderr(), "loaded {} entries", count);
+            Ok(())
+        },
+        Err(err) => Err(err)
+    }
+}
+
+fn compact(cmd: &Command, db: &DB) -> Result<(), error> {
+    let (start, end) = match (cmd.key_opt("start"), cmd.key_opt("end")) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(err), _) | (_, Err(err)) => return Err(err)
+    };
+    db.compact_range(start.as_ref().map(|key| key.as_slice()),
+                     end.as_ref().map(|key| key.as_slice()));
+    Ok(())
+}
+
+fn verify(cmd: &Command, db: &DB) -> Result<(), error> {
+    let (start, end) = match (cmd.key_opt("start"), cmd.key_opt("end")) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(err), _) | (_, Err(err)) => return Err(err)
+    };
+    let range = match (&start, &end) {
+        (&Some(ref start), &Some(ref end)) => Some((start.as_slice(), end.as_slice())),
+        (&None, &None) => None,
+        _ => return Err(~"verify: --start and --end go together")
+    };
+    let report = db.verify(range);
+    println!("{} entries, {} bytes checked", report.entries, report.bytes);
+    let describe = |key: &Option<~[u8]>, missing: &str| match *key {
+        Some(ref key) => encode(cmd.keys, *key),
+        None => missing.to_owned()
+    };
+    for corrupt in report.corrupt.iter() {
+        println!("corrupt: after {} before {}: {}", describe(&corrupt.after, "(start)"),
+                 describe(&corrupt.before, "(end)"), corrupt.error);
+    }
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(format!("{} corrupt ranges", report.corrupt.len()))
+    }
+}
+
+// Compare snapshots of two databases. Lines start with `-` for keys only
+// in the first, `+` for keys only in the second and `~` for changed values.
+fn diff_dbs(cmd: &Command, db: &DB) -> Result<(), error> {
+    let path = match cmd.arg(0) {
+        Ok(path) => path,
+        Err(err) => return Err(err)
+    };
+    let other = match DB::open(path, []) {
+        Ok(other) => other,
+        Err(err) => return Err(format!("{}: {}", path, err))
+    };
+    let mut counts = [0u64, 0, 0];
+    let res = {
+        let a = db.snapshot();
+        let b = other.snapshot();
+        let mut diffs = diff(a.iter([]), b.iter([]));
+        for d in diffs.by_ref() {
+            match d {
+                OnlyInA(key, value) => {
+                    println!("- {} => {}", encode(cmd.keys, key), encode(cmd.values, value));
+                    counts[0] += 1;
+                },
+                OnlyInB(key, value) => {
+                    println!("+ {} => {}", encode(cmd.keys, key), encode(cmd.values, value));
+                    counts[1] += 1;
+                },
+                Changed(key, a, b) => {
+                    println!("~ {} => {} | {}", encode(cmd.keys, key), encode(cmd.values, a),
+                             encode(cmd.values, b));
+                    counts[2] += 1;
+                }
+            }
+        }
+        // An iterator that met an error has skipped entries, which then
+        // pass for differences.
+        let (a, b) = diffs.streams();
+        match (a.get_error(), b.get_error()) {
+            (Some(err), _) => Err(err),
+            (None, Some(err)) => Err(format!("{}: {}", path, err)),
+            (None, None) => Ok(())
+        }
+    };
+    other.close();
+    match res {
+        Ok(_) => {
+            writeln!(&mut std::io::stderr(), "{} removed, {} added, {} changed",
+                     counts[0], counts[1], counts[2]);
+            Ok(())
+        },
+        Err(err) => Err(err)
+    }
+}
+
+fn stats(_: &Command, db: &DB) -> Result<(), error> {
+    for level in range(0, NUM_LEVELS) {
+        let name = format!("leveldb.num-files-at-level{}", level);
+        match db.property_value(name) {
+            Some(value) => println!("{}: {}", name, value),
+            None => {}
+        }
+    }
+    for name in PROPERTIES.iter() {
+        match db.property_value(*name) {
+            Some(value) => println!("{}:\n{}", *name, value),
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+fn sizes(cmd: &Command, db: &DB) -> Result<(), error> {
+    if cmd.args.len() % 2 != 0 {
+        return Err(~"sizes: ranges are given as start/end pairs");
+    }
+    let mut bounds = ~[];
+    for arg in cmd.args.iter() {
+        match decode(cmd.keys, *arg) {
+            Ok(key) => bounds.push(key),
+            Err(err) => return Err(err)
+        }
+    }
+    if bounds.is_empty() {
+        // The whole keyspace, as far as printable keys go.
+        bounds.push(~[]);
+        bounds.push(~[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+    let ranges: ~[(&[u8], &[u8])] = bounds.chunks(2).map(|pair| {
+        (pair[0].as_slice(), pair[1].as_slice())
+    }).collect();
+    let sizes = db.approximate_sizes(ranges);
+    for (&(start, end), size) in ranges.iter().zip(sizes.iter()) {
+        println!("[{}, {}): {}", encode(cmd.keys, start), encode(cmd.keys, end), *size);
+    }
+    Ok(())
+}
+
+// Print the structure and entries of a table file. Entries of a damaged
+// block are replaced by its error, and the dump goes on.
+fn dump_table(cmd: &Command) -> Result<(), error> {
+    let table = match Table::open(&Path::new(cmd.path.as_slice())) {
+        Ok(table) => table,
+        Err(err) => return Err(err)
+    };
+    println!("metaindex: offset {} size {}", table.footer.metaindex.offset,
+             table.footer.metaindex.size);
+    println!("index: offset {} size {}", table.footer.index.offset, table.footer.index.size);
+    match table.filter {
+        Some((ref name, ref filter)) => {
+            println!("filter: {} ({} filters, base 2^{})", *name, filter.filters.len(),
+                     filter.base_lg)
+        },
+        None => println!("filter: none")
+    }
+    let blocks = match table.data_blocks() {
+        Ok(blocks) => blocks,
+        Err(err) => return Err(err)
+    };
+    for &(_, ref handle) in blocks.iter() {
+        println!("data block: offset {} size {}", handle.offset, handle.size);
+    }
+
+    let mut errors = 0u;
+    for entry in table.iter() {
+        match entry {
+            Ok((key, value)) => match key.value_type {
+                TypeValue => println!("{} @ {} : put => {}", encode(cmd.keys, key.user_key),
+                                      key.sequence, encode(cmd.values, value)),
+                TypeDeletion => println!("{} @ {} : del", encode(cmd.keys, key.user_key),
+                                         key.sequence)
+            },
+            Err(err) => {
+                println!("error: {}", err);
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        Err(format!("{} errors", errors))
+    } else {
+        Ok(())
+    }
+}
+
+// Prints the updates of a batch.
+struct BatchPrinter {
+    keys: Encoding,
+    values: Encoding
+}
+
+impl WriteBatchVisitor for BatchPrinter {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        println!("  put {} => {}", encode(self.keys, key), encode(self.values, value));
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        println!("  del {}", encode(self.keys, key));
+    }
+}
+
+// Print the batches of a write-ahead log and the damage found in it.
+fn dump_log(cmd: &Command) -> Result<(), error> {
+    let reader = match LogReader::open(&Path::new(cmd.path.as_slice())) {
+        Ok(reader) => reader,
+        Err(err) => return Err(err)
+    };
+    let mut printer = BatchPrinter {
+        keys: cmd.keys,
+        values: cmd.values
+    };
+    let mut errors = 0u;
+    for record in reader {
+        match record {
+            Ok((offset, rep)) => {
+                println!("batch at {}:", offset);
+                match decode_batch(rep, &mut printer as &mut WriteBatchVisitor) {
+                    Ok((seq, count)) => println!("  sequence {}, {} updates", seq, count),
+                    Err(err) => {
+                        println!("  error: {}", err);
+                        errors += 1;
+                    }
+                }
+            },
+            Err(Corrupt(offset, len, reason)) => {
+                println!("corrupt: {} bytes at {}: {}", len, offset, reason);
+                errors += 1;
+            },
+            Err(Torn(offset)) => {
+                println!("torn tail: record at {} is incomplete", offset);
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        Err(format!("{} errors", errors))
+    } else {
+        Ok(())
+    }
+}
+
+fn describe_file(cmd: &Command, file: &FileMetaData) -> ~str {
+    format!("#{} {} bytes [{} @ {} .. {} @ {}]", file.number, file.size,
+            encode(cmd.keys, file.smallest.user_key), file.smallest.sequence,
+            encode(cmd.keys, file.largest.user_key), file.largest.sequence)
+}
+
+fn print_edit(cmd: &Command, i: uint, edit: &VersionEdit) {
+    println!("edit {}:", i);
+    match edit.comparator {
+        Some(ref name) => println!("  comparator {}", *name),
+        None => {}
+    }
+    let counters = [("log number", edit.log_number),
+                    ("prev log number", edit.prev_log_number),
+                    ("next file", edit.next_file_number),
+                    ("last sequence", edit.last_sequence)];
+    for &(name, value) in counters.iter() {
+        match value {
+            Some(n) => println!("  {} {}", name, n),
+            None => {}
+        }
+    }
+    for &(level, ref key) in edit.compact_pointers.iter() {
+        println!("  compact pointer level {}: {} @ {}", level, encode(cmd.keys, key.user_key),
+                 key.sequence);
+    }
+    for &(level, number) in edit.deleted_files.iter() {
+        println!("  delete level {}: #{}", level, number);
+    }
+    for &(level, ref file) in edit.new_files.iter() {
+        println!("  add level {}: {}", level, describe_file(cmd, file));
+    }
+}
+
+// Print the current version of a manifest, given it or its database.
+fn dump_manifest(cmd: &Command) -> Result<(), error> {
+    let path = Path::new(cmd.path.as_slice());
+    let is_manifest = match path.filename_str() {
+        Some(name) => name.starts_with("MANIFEST-"),
+        None => false
+    };
+    let path = if is_manifest {
+        path
+    } else {
+        match Version::current_manifest(&path) {
+            Ok(path) => path,
+            Err(err) => return Err(err)
+        }
+    };
+    let (version, edits, damage) = match Version::replay(&path) {
+        Ok(res) => res,
+        Err(err) => return Err(err)
+    };
+    if cmd.matches.opt_present("edits") {
+        for (i, edit) in edits.iter().enumerate() {
+            print_edit(cmd, i, edit);
+        }
+    }
+    println!("manifest: {} ({} edits)", path.display(), edits.len());
+    println!("comparator: {}", version.comparator.clone().unwrap_or(~"(unset)"));
+    println!("log number: {}", version.log_number);
+    println!("prev log number: {}", version.prev_log_number);
+    println!("next file: {}", version.next_file_number);
+    println!("last sequence: {}", version.last_sequence);
+    for (level, files) in version.files.iter().enumerate() {
+        let total = files.iter().fold(0u64, |total, file| total + file.size);
+        println!("level {}: {} files, {} bytes", level, files.len(), total);
+        for file in files.iter() {
+            println!("  {}", describe_file(cmd, file));
+        }
+        match version.compact_pointers[level] {
+            Some(ref key) => println!("  compact pointer: {}", encode(cmd.keys, key.user_key)),
+            None => {}
+        }
+    }
+    for d in damage.iter() {
+        match *d {
+            Corrupt(offset, len, ref reason) => {
+                println!("corrupt: {} bytes at {}: {}", len, offset, *reason)
+            },
+            Torn(offset) => println!("torn tail: record at {} is incomplete", offset)
+        }
+    }
+    if damage.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} damaged regions", damage.len()))
+    }
+}
+
+fn run_shell(cmd: &Command, db: &DB) -> Result<(), error> {
+    shell::run(db, cmd.keys, cmd.values)
+}
+
+fn run(args: ~[~str]) -> Result<(), error> {
+    let opts = ~[
+        optopt("key-format"),
+        optopt("value-format"),
+        optopt("format"),
+        optopt("prefix"),
+        optopt("start"),
+        optopt("end"),
+        optopt("limit"),
+        optopt("out"),
+        optopt("in"),
+        optflag("reverse"),
+        optflag("edits"),
+        optflag("create")
+    ];
+    let matches = match getopts(args.tail(), opts) {
+        Ok(matches) => matches,
+        Err(fail) => return Err(format!("{}\n{}", fail.to_err_msg(), USAGE))
+    };
+    if matches.free.len() < 2 {
+        return Err(USAGE.to_owned());
+    }
+    let keys = match parse_encoding(matches.opt_str("key-format")) {
+        Ok(encoding) => encoding,
+        Err(err) => return Err(err)
+    };
+    let values = match parse_encoding(matches.opt_str("value-format")) {
+        Ok(encoding) => encoding,
+        Err(err) => return Err(err)
+    };
+    let name = matches.free[0].clone();
+    let path = matches.free[1].clone();
+    let args = matches.free.slice_from(2).to_owned();
+    let cmd = Command {
+        name: name,
+        path: path,
+        args: args,
+        matches: matches,
+        keys: keys,
+        values: values
+    };
+
+    // These work on the files of a database that is not open.
+    match cmd.name.as_slice() {
+        "repair" => return DB::repair(cmd.path, []),
+        "destroy" => return DB::destroy(cmd.path, []),
+        "sst" => return dump_table(&cmd),
+        "wal" => return dump_log(&cmd),
+        "manifest" => return dump_manifest(&cmd),
+        _ => {}
+    }
+
+    let f: fn(&Command, &DB) -> Result<(), error> = match cmd.name.as_slice() {
+        "get" => get,
+        "put" => put,
+        "delete" => delete,
+        "scan" => scan,
+        "count" => count,
+        "dump" => dump,
+        "load" => load,
+        "compact" => compact,
+        "verify" => verify,
+        "diff" => diff_dbs,
+        "stats" => stats,
+        "sizes" => sizes,
+        "shell" => run_shell,
+        _ => return Err(format!("unknown command {}\n{}", cmd.name, USAGE))
+    };
+    let db = match cmd.open() {
+        Ok(db) => db,
+        Err(err) => return Err(err)
+    };
+    let res = f(&cmd, &*db);
+    db.close();
+    res
+}
+
+fn main() {
+    match run(os::args()) {
+        Ok(_) => {},
+        Err(err) => {
+            writeln!(&mut std::io::stderr(), "leveldb-cli: {}", err);
+            os::set_exit_status(1);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! The interactive `shell` command.
+//!
+//! The shell keeps one database open across commands, together with a
+//! cursor, an optional pinned snapshot that reads and the cursor go
+//! through, and an optional batch staging writes until `commit`.
+
+use std::io::{stdin, stdout};
+use std::io::buffered::BufferedReader;
+
+use leveldb::{DB, DBIterator, Snapshot, WriteBatch};
+
+use super::{Encoding, decode, encode, error};
+
+static HELP: &'static str = "commands:
+    get <key>             read a key, through the snapshot if one is pinned
+    put <key> <value>     write a key, or stage it if a batch is open
+    del <key>             delete a key, or stage it if a batch is open
+    seek <key>            move the cursor to the first key at or after <key>
+    next | prev           move the cursor one entry
+    first | last          move the cursor to the first or last entry
+    snapshot | release    pin or release a snapshot
+    begin | commit | abort    open, write or discard a batch
+    history               list previous commands
+    !<n>                  run command <n> of the history again
+    help | quit
+
+Arguments containing spaces can be double-quoted.";
+
+struct Shell<'r> {
+    db: &'r DB,
+    snapshot: Option<Snapshot<'r>>,
+    cursor: Option<DBIterator>,
+    batch: Option<(WriteBatch, uint)>,
+    history: ~[~str],
+    keys: Encoding,
+    values: Encoding
+}
+
+// Split a line into words, keeping double-quoted strings together.
+fn tokenize(line: &str) -> Result<~[~str], error> {
+    let mut words = ~[];
+    let mut word = ~"";
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in line.chars() {
+        if quoted {
+            if c == '"' {
+                quoted = false;
+            } else {
+                word.push_char(c);
+            }
+        } else if c == '"' {
+            quoted = true;
+            in_word = true;
+        } else if c.is_whitespace() {
+            if in_word {
+                words.push(word.clone());
+                word = ~"";
+                in_word = false;
+            }
+        } else {
+            word.push_char(c);
+            in_word = true;
+        }
+    }
+    if quoted {
+        return Err(~"unterminated quote");
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+impl<'r> Shell<'r> {
+    fn key(&self, words: &[~str], i: uint) -> Result<~[u8], error> {
+        if i < words.len() {
+            decode(self.keys, words[i])
+        } else {
+            Err(~"missing key")
+        }
+    }
+
+    fn value(&self, words: &[~str], i: uint) -> Result<~[u8], error> {
+        if i < words.len() {
+            decode(self.values, words[i])
+        } else {
+            Err(~"missing value")
+        }
+    }
+
+    fn new_iter(&self) -> DBIterator {
+        match self.snapshot {
+            Some(ref snapshot) => snapshot.iter([]),
+            None => self.db.iter([])
+        }
+    }
+
+    fn show_cursor(&self) {
+        let it = match self.cursor {
+            Some(ref it) => it,
+            None => return
+        };
+        if it.is_valid() {
+            println!("{} => {}", encode(self.keys, it.key()), encode(self.values, it.value()));
+        } else {
+            match it.get_error() {
+                Some(err) => println!("error: {}", err),
+                None => println!("(end)")
+            }
+        }
+    }
+
+    // Move the cursor with `f`, creating it at the first entry if needed.
+    fn move_cursor(&mut self, f: |&mut DBIterator|) {
+        if self.cursor.is_none() {
+            self.cursor = Some(self.new_iter());
+        }
+        f(self.cursor.get_mut_ref());
+        self.show_cursor();
+    }
+
+    fn get(&self, words: &[~str]) -> Result<(), error> {
+        let key = match self.key(words, 1) {
+            Ok(key) => key,
+            Err(err) => return Err(err)
+        };
+        let res = match self.snapshot {
+            Some(ref snapshot) => snapshot.get_opt(key, []),
+            None => self.db.get_opt(key, [])
+        };
+        match res {
+            Ok(Some(value)) => println!("{}", encode(self.values, value)),
+            Ok(None) => println!("(not found)"),
+            Err(err) => return Err(err)
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, words: &[~str]) -> Result<(), error> {
+        let key = match self.key(words, 1) {
+            Ok(key) => key,
+            Err(err) => return Err(err)
+        };
+        let value = match self.value(words, 2) {
+            Ok(value) => value,
+            Err(err) => return Err(err)
+        };
+        match self.batch {
+            Some((ref mut batch, ref mut staged)) => {
+                batch.put(key, value);
+                *staged += 1;
+                Ok(())
+            },
+            None => self.db.put(key, value, [])
+        }
+    }
+
+    fn delete(&mut self, words: &[~str]) -> Result<(), error> {
+        let key = match self.key(words, 1) {
+            Ok(key) => key,
+            Err(err) => return Err(err)
+        };
+        match self.batch {
+            Some((ref mut batch, ref mut staged)) => {
+                batch.delete(key);
+                *staged += 1;
+                Ok(())
+            },
+            None => self.db.delete(key, [])
+        }
+    }
+
+    fn commit(&mut self) -> Result<(), error> {
+        let (batch, staged) = match self.batch.take() {
+            Some(batch) => batch,
+            None => return Err(~"no batch open")
+        };
+        match self.db.write(&batch, []) {
+            Ok(_) => {
+                println!("committed {} updates", staged);
+                Ok(())
+            },
+            Err(err) => {
+                // Keep the batch so the commit can be retried.
+                self.batch = Some((batch, staged));
+                Err(err)
+            }
+        }
+    }
+
+    // Run one command. Returns false when the shell should exit.
+    fn run(&mut self, words: &[~str]) -> Result<bool, error> {
+        let res = match words[0].as_slice() {
+            "get" => self.get(words),
+            "put" => self.put(words),
+            "del" | "delete" => self.delete(words),
+            "seek" => match self.key(words, 1) {
+                Ok(key) => {
+                    self.move_cursor(|it| it.seek(key));
+                    Ok(())
+                },
+                Err(err) => Err(err)
+            },
+            "next" => {
+                self.move_cursor(|it| if it.is_valid() { it.next(); });
+                Ok(())
+            },
+            "prev" => {
+                self.move_cursor(|it| if it.is_valid() { it.prev(); });
+                Ok(())
+            },
+            "first" => {
+                self.move_cursor(|it| it.seek_to_first());
+                Ok(())
+            },
+            "last" => {
+                self.move_cursor(|it| it.seek_to_last());
+                Ok(())
+            },
+            "snapshot" => {
+                // The cursor is reopened on the snapshot at its next move.
+                self.cursor = None;
+                self.snapshot = Some(self.db.snapshot());
+                Ok(())
+            },
+            "release" => {
+                if self.snapshot.is_none() {
+                    Err(~"no snapshot pinned")
+                } else {
+                    self.cursor = None;
+                    self.snapshot = None;
+                    Ok(())
+                }
+            },
+            "begin" => {
+                if self.batch.is_some() {
+                    Err(~"a batch is already open")
+                } else {
+                    self.batch = Some((WriteBatch::new(), 0));
+                    Ok(())
+                }
+            },
+            "commit" => self.commit(),
+            "abort" => match self.batch.take() {
+                Some((_, staged)) => {
+                    println!("discarded {} updates", staged);
+                    Ok(())
+                },
+                None => Err(~"no batch open")
+            },
+            "history" => {
+                for (i, line) in self.history.iter().enumerate() {
+                    println!("{:4u}  {}", i + 1, *line);
+                }
+                Ok(())
+            },
+            "help" => {
+                println!("{}", HELP);
+                Ok(())
+            },
+            "quit" | "exit" => return Ok(false),
+            other => Err(format!("unknown command {}; try help", other))
+        };
+        res.map(|_| true)
+    }
+
+    fn prompt(&self) {
+        let mut state = ~"";
+        if self.snapshot.is_some() {
+            state.push_str(" snapshot");
+        }
+        match self.batch {
+            Some((_, staged)) => state.push_str(format!(" batch:{}", staged)),
+            None => {}
+        }
+        print!("leveldb{}> ", state);
+        stdout().flush();
+    }
+}
+
+/// Read commands from standard input until `quit` or end of input.
+pub fn run(db: &DB, keys: Encoding, values: Encoding) -> Result<(), error> {
+    let mut shell = Shell {
+        db: db,
+        snapshot: None,
+        cursor: None,
+        batch: None,
+        history: ~[],
+        keys: keys,
+        values: values
+    };
+    let mut input = BufferedReader::new(stdin());
+    loop {
+        shell.prompt();
+        let line = match input.read_line() {
+            Some(line) => line.trim().to_owned(),
+            None => break
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let line = if line.starts_with("!") {
+            match from_str::<uint>(line.slice_from(1)) {
+                Some(n) if n >= 1 && n <= shell.history.len() => {
+                    let line = shell.history[n - 1].clone();
+                    println!("{}", line);
+                    line
+                },
+                _ => {
+                    println!("error: no command {} in history", line.slice_from(1));
+                    continue;
+                }
+            }
+        } else {
+            line
+        };
+        shell.history.push(line.clone());
+        let words = match tokenize(line) {
+            Ok(words) => words,
+            Err(err) => {
+                println!("error: {}", err);
+                continue;
+            }
+        };
+        if words.is_empty() {
+            continue;
+        }
+        match shell.run(words) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(err) => println!("error: {}", err)
+        }
+    }
+    if shell.batch.is_some() {
+        println!("discarding uncommitted batch");
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Online backups.
+//!
+//! A full backup copies a snapshot of the database into a new database in
+//! large batches, so the source stays writable throughout. The change log
+//! itself is not copied. If the log is enabled, the backup is registered
+//! as a follower of the source at the sequence number of the snapshot,
+//! when the snapshot is taken, so that the entries logged since are kept
+//! and an incremental backup can later apply only those.
+
+use super::{DB, Snapshot, BatchWriter, BULK_BATCH_BYTES, error};
+use super::cleveldb::leveldb_iter_destroy;
+use super::options::{CREATE_IF_MISSING, ERROR_IF_EXISTS};
+use super::replication::{Follower, is_log_key};
+
+static LOG_BATCHES: uint = 1000;
+
+fn follower_name(path: &str) -> ~str {
+    format!("backup:{}", path)
+}
+
+// Copy the entries of `snapshot` other than the change log to `backup`.
+fn copy_entries(snapshot: &Snapshot, backup: &DB, progress: |uint, u64|) -> Result<uint, error> {
+    let mut entries = 0u;
+    let mut writer = BatchWriter::new(backup, BULK_BATCH_BYTES);
+    let mut it = snapshot.iter([]);
+    let mut res = Ok(());
+    for (key, value) in it.by_ref() {
+        if is_log_key(key) {
+            continue;
+        }
+        entries += 1;
+        match writer.put(key, value) {
+            Ok(true) => progress(entries, writer.written),
+            Ok(false) => {},
+            Err(err) => {
+                res = Err(err);
+                break;
+            }
+        }
+    }
+    if res.is_ok() {
+        res = match it.get_error() {
+            Some(err) => Err(err),
+            None => writer.flush()
+        };
+    }
+    unsafe {
+        leveldb_iter_destroy(it.iter);
+    }
+    match res {
+        Ok(_) => {},
+        Err(err) => return Err(err)
+    }
+    progress(entries, writer.written);
+    backup.compact_range(None, None);
+    Ok(entries)
+}
+
+impl DB {
+    /// Copy the database into a new database at `path` and return the
+    /// number of entries copied. `progress` is called after every batch
+    /// with the entries and bytes copied so far.
+    pub fn backup_to(&self, path: &str, progress: |uint, u64|) -> Result<uint, error> {
+        let backup = match DB::open(path, [CREATE_IF_MISSING, ERROR_IF_EXISTS]) {
+            Ok(db) => db,
+            Err(err) => return Err(err)
+        };
+        let res = self.copy_snapshot_to(backup, path, progress);
+        backup.close();
+        res
+    }
+
+    fn copy_snapshot_to(&self, backup: &DB, path: &str, progress: |uint, u64|) -> Result<uint, error> {
+        let name = follower_name(path);
+        let (snapshot, seq) = if self.change_log_seq().is_some() {
+            match self.snapshot_for_follower(name) {
+                Ok((snapshot, seq)) => (snapshot, Some(seq)),
+                Err(err) => return Err(err)
+            }
+        } else {
+            (self.snapshot(), None)
+        };
+        let res = copy_entries(&snapshot, backup, progress);
+        match (res, seq) {
+            (Ok(entries), Some(seq)) => match Follower::new(self, backup, name).set_applied_seq(seq) {
+                Ok(_) => Ok(entries),
+                Err(err) => self.abandon_backup(name, err)
+            },
+            (Err(err), Some(_)) => self.abandon_backup(name, err),
+            (res, None) => res
+        }
+    }
+
+    // Unregister the follower of a failed backup, so that it does not hold
+    // back truncation of the change log, and fail with `err`.
+    fn abandon_backup(&self, name: &str, err: error) -> Result<uint, error> {
+        match self.remove_follower(name) {
+            Ok(_) => Err(err),
+            Err(remove_err) => Err(format!("{}; removing follower {} also failed: {}",
+                                           err, name, remove_err))
+        }
+    }
+
+    /// Bring the backup at `path`, made by `backup_to` with the change log
+    /// enabled, up to date by applying the batches logged since. Returns
+    /// the number of batches applied; `progress` is called with the
+    /// number applied so far.
+    pub fn backup_incremental(&self, path: &str, progress: |uint|) -> Result<uint, error> {
+        if self.change_log_seq().is_none() {
+            return Err(~"incremental backups need the change log");
+        }
+        let backup = match DB::open(path, []) {
+            Ok(db) => db,
+            Err(err) => return Err(err)
+        };
+        let res = apply_log(&Follower::new(self, backup, follower_name(path)), progress);
+        backup.close();
+        res
+    }
+}
+
+// Poll `follower` until it is up to date and return the number of batches
+// applied.
+fn apply_log(follower: &Follower, progress: |uint|) -> Result<uint, error> {
+    let mut applied = 0u;
+    loop {
+        match follower.poll(LOG_BATCHES) {
+            Ok(0) => return Ok(applied),
+            Ok(n) => {
+                applied += n;
+                progress(applied);
+            },
+            Err(err) => return Err(err)
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Compare-and-swap on single keys.
+//!
+//! Both operations hold the key's striped lock across their read and
+//! write, so they are linearizable with respect to each other and to the
+//! other key-locking operations of this handle (`update`, `merge`,
+//! `increment`). Plain `put`, `delete` and `write` bypass the locks.
+
+use super::{DB, error};
+use super::locks::LOCK_TIMEOUT_MS;
+use super::options::WriteOption;
+
+impl DB {
+    /// If the value of `key` is `expected` (`None` meaning absent), replace
+    /// it with `new` (`None` deleting it). Returns whether the swap
+    /// happened.
+    pub fn compare_and_swap(&self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>,
+                            options: &[WriteOption]) -> Result<bool, error> {
+        let _guard = match self.key_locks.lock_within([key], LOCK_TIMEOUT_MS) {
+            Ok(guard) => guard,
+            Err(err) => return Err(err)
+        };
+        let current = match self.get_opt(key, []) {
+            Ok(current) => current,
+            Err(err) => return Err(err)
+        };
+        if current.as_ref().map(|v| v.as_slice()) != expected {
+            return Ok(false);
+        }
+        let res = match new {
+            Some(value) => self.put(key, value, options),
+            None => self.delete(key, options)
+        };
+        match res {
+            Ok(_) => Ok(true),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Store `value` under `key` unless the key already exists. Returns
+    /// whether the value was stored.
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8],
+                         options: &[WriteOption]) -> Result<bool, error> {
+        self.compare_and_swap(key, None, Some(value), options)
+    }
+}
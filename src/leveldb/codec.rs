@@ -0,0 +1,144 @@
+//! Structured values stored through `extra::serialize`.
+//!
+//! A `Format` turns values into bytes and back. `Json` is provided; other
+//! encodings can be added by implementing `Format`. The module is built
+//! with `--cfg codec`.
+//!
+//! Input that is not JSON is returned as an error, but `extra::serialize`
+//! decoders fail the task on input that does not have the shape of the
+//! decoded type. Wrap a format in `Isolated` to decode in a separate task
+//! and get such failures back as errors, at the cost of a task per value.
+
+use std::io;
+use std::io::mem::MemWriter;
+use std::str;
+use std::task;
+
+use extra::hex::ToHex;
+use extra::json;
+use extra::serialize::{Decodable, Encodable};
+
+use super::{DB, DBIterator, error};
+use super::options::{ReadOption, WriteOption};
+
+/// A serialization format for values of type `T`.
+pub trait Format<T> {
+    fn encode(&self, value: &T) -> ~[u8];
+    fn decode(&self, bytes: &[u8]) -> Result<T, ~str>;
+}
+
+/// JSON, using `extra::json`
+#[deriving(Clone)]
+pub struct Json;
+
+/// Decodes with format `F` in a separate task, so that decoder failures
+/// are returned as errors
+#[deriving(Clone)]
+pub struct Isolated<F>(F);
+
+impl<'a, T: Encodable<json::Encoder<'a>> + Decodable<json::Decoder>> Format<T> for Json {
+    fn encode(&self, value: &T) -> ~[u8] {
+        let mut writer = MemWriter::new();
+        {
+            let mut encoder = json::Encoder::new(&mut writer as &mut io::Writer);
+            value.encode(&mut encoder);
+        }
+        writer.unwrap()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, ~str> {
+        let s = match str::from_utf8_opt(bytes) {
+            Some(s) => s,
+            None => return Err(~"invalid utf-8")
+        };
+        match json::from_str(s) {
+            Ok(j) => {
+                let mut decoder = json::Decoder::new(j);
+                Ok(Decodable::decode(&mut decoder))
+            },
+            Err(err) => Err(err.to_str())
+        }
+    }
+}
+
+impl<T: Send, F: Format<T> + Clone + Send> Format<T> for Isolated<F> {
+    fn encode(&self, value: &T) -> ~[u8] {
+        let Isolated(ref format) = *self;
+        format.encode(value)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, ~str> {
+        let Isolated(ref format) = *self;
+        let format = format.clone();
+        let bytes = bytes.to_owned();
+        match task::try(proc() format.decode(bytes)) {
+            Ok(res) => res,
+            Err(_) => Err(~"value does not have the expected shape")
+        }
+    }
+}
+
+fn decode_error(key: &[u8], err: ~str) -> error {
+    format!("cannot decode value of key {}: {}", key.to_hex(), err)
+}
+
+impl DB {
+    /// Encode `value` with `format` and store it under `key`.
+    pub fn put_serialized<T, F: Format<T>>(&self, format: &F, key: &[u8], value: &T,
+                                           options: &[WriteOption]) -> Result<(), error> {
+        self.put(key, format.encode(value), options)
+    }
+
+    /// Fetch the value under `key` and decode it with `format`.
+    pub fn get_deserialized<T, F: Format<T>>(&self, format: &F, key: &[u8],
+                                             options: &[ReadOption]) -> Result<Option<T>, error> {
+        match self.get_opt(key, options) {
+            Ok(Some(bytes)) => match format.decode(bytes) {
+                Ok(value) => Ok(Some(value)),
+                Err(err) => Err(decode_error(key, err))
+            },
+            Ok(None) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Iterate over the database, decoding every value with `format`.
+    pub fn iter_deserialized<'r, T, F: Format<T>>(&self, format: &'r F,
+                                                  options: &[ReadOption]) -> DeserializedIterator<'r, T, F> {
+        DeserializedIterator {
+            iter: self.iter(options),
+            format: format
+        }
+    }
+}
+
+/// Iterator over `(key, decoded value)` pairs.
+pub struct DeserializedIterator<'r, T, F> {
+    iter: DBIterator,
+    format: &'r F
+}
+
+impl<'r, T, F: Format<T>> DeserializedIterator<'r, T, F> {
+    pub fn seek(&mut self, key: &[u8]) {
+        self.iter.seek(key);
+    }
+
+    pub fn get_error(&self) -> Option<error> {
+        self.iter.get_error()
+    }
+}
+
+impl<'r, T, F: Format<T>> Iterator<(~[u8], Result<T, error>)> for DeserializedIterator<'r, T, F> {
+    fn next(&mut self) -> Option<(~[u8], Result<T, error>)> {
+        match self.iter.next() {
+            Some((key, bytes)) => {
+                let value = match self.format.decode(bytes) {
+                    Ok(value) => Ok(value),
+                    Err(err) => Err(decode_error(key, err))
+                };
+                Some((key, value))
+            },
+            None => None
+        }
+    }
+}
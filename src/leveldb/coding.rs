@@ -0,0 +1,210 @@
+// Encodings shared with LevelDB's on-disk formats: little-endian fixed
+// width integers, varints and the WriteBatch representation. Also the
+// big-endian integers the wrapper uses in its own keys and values, where
+// byte order must match numeric order.
+
+use super::{WriteBatchVisitor, error};
+
+static TYPE_DELETION: u8 = 0;
+static TYPE_VALUE: u8 = 1;
+
+pub fn put_fixed32(buf: &mut ~[u8], n: u32) {
+    for i in range(0u32, 4) {
+        buf.push((n >> (8 * i)) as u8);
+    }
+}
+
+pub fn put_fixed64(buf: &mut ~[u8], n: u64) {
+    for i in range(0u64, 8) {
+        buf.push((n >> (8 * i)) as u8);
+    }
+}
+
+pub fn get_fixed32(bytes: &[u8]) -> u32 {
+    let mut n = 0u32;
+    for i in range(0u, 4) {
+        n |= (bytes[i] as u32) << (8 * i as u32);
+    }
+    n
+}
+
+pub fn get_fixed64(bytes: &[u8]) -> u64 {
+    let mut n = 0u64;
+    for i in range(0u, 8) {
+        n |= (bytes[i] as u64) << (8 * i as u64);
+    }
+    n
+}
+
+pub fn put_varint64(buf: &mut ~[u8], n: u64) {
+    let mut n = n;
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+pub fn put_varint32(buf: &mut ~[u8], n: u32) {
+    put_varint64(buf, n as u64);
+}
+
+/// Decode a varint at `*pos`, advancing `*pos` past it.
+pub fn get_varint64(bytes: &[u8], pos: &mut uint) -> Option<u64> {
+    let mut n = 0u64;
+    let mut shift = 0u64;
+    while *pos < bytes.len() && shift <= 63 {
+        let b = bytes[*pos];
+        *pos += 1;
+        n |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(n);
+        }
+        shift += 7;
+    }
+    None
+}
+
+pub fn get_varint32(bytes: &[u8], pos: &mut uint) -> Option<u32> {
+    match get_varint64(bytes, pos) {
+        Some(n) if n <= 0xffffffff => Some(n as u32),
+        _ => None
+    }
+}
+
+pub fn put_length_prefixed(buf: &mut ~[u8], bytes: &[u8]) {
+    put_varint32(buf, bytes.len() as u32);
+    buf.push_all(bytes);
+}
+
+/// Decode a varint-length-prefixed slice at `*pos`, advancing `*pos`.
+pub fn get_length_prefixed<'a>(bytes: &'a [u8], pos: &mut uint) -> Option<&'a [u8]> {
+    match get_varint32(bytes, pos) {
+        Some(len) if *pos + (len as uint) <= bytes.len() => {
+            let start = *pos;
+            *pos += len as uint;
+            Some(bytes.slice(start, *pos))
+        },
+        _ => None
+    }
+}
+
+pub fn put_be32(buf: &mut ~[u8], n: u32) {
+    for i in range(0u32, 4) {
+        buf.push((n >> (24 - 8 * i)) as u8);
+    }
+}
+
+pub fn put_be64(buf: &mut ~[u8], n: u64) {
+    for i in range(0u64, 8) {
+        buf.push((n >> (56 - 8 * i)) as u8);
+    }
+}
+
+pub fn get_be32(bytes: &[u8]) -> u32 {
+    let mut n = 0u32;
+    for i in range(0u, 4) {
+        n = (n << 8) | (bytes[i] as u32);
+    }
+    n
+}
+
+pub fn get_be64(bytes: &[u8]) -> u64 {
+    let mut n = 0u64;
+    for i in range(0u, 8) {
+        n = (n << 8) | (bytes[i] as u64);
+    }
+    n
+}
+
+pub fn encode_u64(n: u64) -> ~[u8] {
+    let mut bytes = ~[];
+    put_be64(&mut bytes, n);
+    bytes
+}
+
+/// Decode a value written by `encode_u64`; `None` unless it is exactly
+/// eight bytes.
+pub fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() != 8 {
+        return None;
+    }
+    Some(get_be64(bytes))
+}
+
+pub fn put_be32_prefixed(buf: &mut ~[u8], bytes: &[u8]) {
+    put_be32(buf, bytes.len() as u32);
+    buf.push_all(bytes);
+}
+
+/// Decode a big-endian u32 length-prefixed slice at `*pos`, advancing
+/// `*pos`.
+pub fn get_be32_prefixed<'a>(bytes: &'a [u8], pos: &mut uint) -> Option<&'a [u8]> {
+    if bytes.len() - *pos < 4 {
+        return None;
+    }
+    let len = get_be32(bytes.slice_from(*pos)) as uint;
+    if bytes.len() - *pos - 4 < len {
+        return None;
+    }
+    let start = *pos + 4;
+    *pos = start + len;
+    Some(bytes.slice(start, *pos))
+}
+
+/// Encode updates in LevelDB's WriteBatch representation: a fixed64
+/// sequence number, a fixed32 count, then one record per update.
+pub fn encode_batch(seq: u64, updates: &[(~[u8], Option<~[u8]>)]) -> ~[u8] {
+    let mut rep = ~[];
+    put_fixed64(&mut rep, seq);
+    put_fixed32(&mut rep, updates.len() as u32);
+    for &(ref key, ref value) in updates.iter() {
+        match *value {
+            Some(ref value) => {
+                rep.push(TYPE_VALUE);
+                put_length_prefixed(&mut rep, *key);
+                put_length_prefixed(&mut rep, *value);
+            },
+            None => {
+                rep.push(TYPE_DELETION);
+                put_length_prefixed(&mut rep, *key);
+            }
+        }
+    }
+    rep
+}
+
+/// Pass the records of a WriteBatch representation to `visitor` and
+/// return its sequence number and count.
+pub fn decode_batch(rep: &[u8], visitor: &mut WriteBatchVisitor) -> Result<(u64, u32), error> {
+    if rep.len() < 12 {
+        return Err(~"write batch too small");
+    }
+    let seq = get_fixed64(rep.slice(0, 8));
+    let count = get_fixed32(rep.slice(8, 12));
+    let mut pos = 12u;
+    let mut found = 0u32;
+    while pos < rep.len() {
+        let tag = rep[pos];
+        pos += 1;
+        let key = match get_length_prefixed(rep, &mut pos) {
+            Some(key) => key,
+            None => return Err(~"bad write batch key")
+        };
+        if tag == TYPE_VALUE {
+            match get_length_prefixed(rep, &mut pos) {
+                Some(value) => visitor.put(key, value),
+                None => return Err(~"bad write batch value")
+            }
+        } else if tag == TYPE_DELETION {
+            visitor.delete(key);
+        } else {
+            return Err(format!("unknown write batch record type {}", tag));
+        }
+        found += 1;
+    }
+    if found != count {
+        return Err(format!("write batch has {} records, header says {}", found, count));
+    }
+    Ok((seq, count))
+}
@@ -0,0 +1,67 @@
+//! Atomic counters.
+//!
+//! A counter is a value holding a big-endian two's-complement i64. A
+//! missing key counts as 0. Increments take the striped key locks, so they
+//! are atomic with respect to each other and to `DB::update`, and a batch
+//! of increments is committed with a single write.
+
+use std::hashmap::HashMap;
+
+use extra::hex::ToHex;
+
+use super::{DB, WriteBatch, error};
+use super::locks::LOCK_TIMEOUT_MS;
+use super::coding::{decode_u64, encode_u64};
+use super::options::WriteOption;
+
+/// Decode a counter value.
+pub fn decode_counter(bytes: &[u8]) -> Option<i64> {
+    decode_u64(bytes).map(|n| n as i64)
+}
+
+impl DB {
+    /// Add `delta` to the counter under `key` and return the new value.
+    pub fn increment(&self, key: &[u8], delta: i64, options: &[WriteOption]) -> Result<i64, error> {
+        match self.increment_many([(key, delta)], options) {
+            Ok(values) => Ok(values[0]),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Apply several increments atomically and return the new value of
+    /// each counter, in the order given. A key appearing several times
+    /// receives the sum of its deltas.
+    pub fn increment_many(&self, increments: &[(&[u8], i64)],
+                          options: &[WriteOption]) -> Result<~[i64], error> {
+        let keys: ~[&[u8]] = increments.iter().map(|&(key, _)| key).collect();
+        let _guard = match self.key_locks.lock_within(keys, LOCK_TIMEOUT_MS) {
+            Ok(guard) => guard,
+            Err(err) => return Err(err)
+        };
+        let mut values: HashMap<~[u8], i64> = HashMap::new();
+        for &(key, delta) in increments.iter() {
+            let key = key.to_owned();
+            let current = match values.find(&key) {
+                Some(&value) => value,
+                None => match self.get_opt(key, []) {
+                    Ok(Some(bytes)) => match decode_counter(bytes) {
+                        Some(value) => value,
+                        None => return Err(format!("value of key {} is not a counter",
+                                                   key.to_hex()))
+                    },
+                    Ok(None) => 0,
+                    Err(err) => return Err(err)
+                }
+            };
+            values.insert(key, current + delta);
+        }
+        let mut batch = WriteBatch::new();
+        for (key, &value) in values.iter() {
+            batch.put(*key, encode_u64(value as u64));
+        }
+        match self.write(&batch, options) {
+            Ok(_) => Ok(increments.iter().map(|&(key, _)| *values.get(&key.to_owned())).collect()),
+            Err(err) => Err(err)
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! CRC-32C (Castagnoli), the checksum used by LevelDB's file formats.
+
+static POLY: u32 = 0x82f63b78;
+static MASK_DELTA: u32 = 0xa282ead8;
+
+/// Extend `crc` with `bytes`.
+pub fn extend(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes.iter() {
+        crc ^= b as u32;
+        for _ in range(0, 8) {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn value(bytes: &[u8]) -> u32 {
+    extend(0, bytes)
+}
+
+/// Mask a CRC the way LevelDB does before storing it, since computing the
+/// CRC of a string containing embedded CRCs is problematic.
+pub fn mask(crc: u32) -> u32 {
+    ((crc >> 15) | (crc << 17)) + MASK_DELTA
+}
+
+pub fn unmask(masked: u32) -> u32 {
+    let rot = masked - MASK_DELTA;
+    (rot >> 17) | (rot << 15)
+}
@@ -0,0 +1,62 @@
+//! Deleting a range of keys.
+//!
+//! LevelDB has no range delete. The keys of the range are read from an
+//! iterator and deleted in batches of bounded size, so a large range is
+//! not deleted atomically.
+
+use super::{DB, BatchWriter, BULK_BATCH_BYTES, error};
+use super::cleveldb::leveldb_iter_destroy;
+use super::options::{DeleteRangeOption, BATCH_BYTES, COMPACT};
+
+impl DB {
+    /// Delete every key in `[start, end)` and return how many were deleted.
+    pub fn delete_range(&self, start: &[u8], end: &[u8],
+                        options: &[DeleteRangeOption]) -> Result<uint, error> {
+        let mut batch_bytes = BULK_BATCH_BYTES;
+        let mut compact = false;
+        for option in options.iter() {
+            match *option {
+                BATCH_BYTES(n) => batch_bytes = n,
+                COMPACT => compact = true
+            }
+        }
+
+        let mut count = 0u;
+        let mut writer = BatchWriter::new(self, batch_bytes);
+        let mut res = Ok(());
+        let mut it = self.iter([]);
+        it.seek(start);
+        while it.is_valid() {
+            let key = it.key();
+            if key.as_slice() >= end {
+                break;
+            }
+            match writer.delete(key) {
+                Ok(_) => {},
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            }
+            count += 1;
+            it.next();
+        }
+        if res.is_ok() {
+            res = match it.get_error() {
+                Some(err) => Err(err),
+                None => writer.flush()
+            };
+        }
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        match res {
+            Ok(_) => {},
+            Err(err) => return Err(err)
+        }
+        if compact {
+            self.compact_range(Some(start), Some(end));
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,90 @@
+//! Differences between two sorted streams of entries, such as the
+//! iterators of two databases or of two snapshots of one. The streams are
+//! merged one entry at a time, so diffs of any size run in constant
+//! memory. A database iterator that fails looks like a stream missing
+//! entries, so callers check `streams` for errors before trusting a diff.
+
+#[deriving(Eq, Clone)]
+pub enum Diff {
+    /// A key and its value found only in the first stream
+    OnlyInA(~[u8], ~[u8]),
+    /// A key and its value found only in the second stream
+    OnlyInB(~[u8], ~[u8]),
+    /// A key and its values in the first and the second stream
+    Changed(~[u8], ~[u8], ~[u8])
+}
+
+impl Diff {
+    pub fn key<'a>(&'a self) -> &'a [u8] {
+        match *self {
+            OnlyInA(ref key, _) => key.as_slice(),
+            OnlyInB(ref key, _) => key.as_slice(),
+            Changed(ref key, _, _) => key.as_slice()
+        }
+    }
+}
+
+/// Iterator over the differences between two streams, in key order
+pub struct DiffIterator<A, B> {
+    priv a: A,
+    priv b: B,
+    priv next_a: Option<(~[u8], ~[u8])>,
+    priv next_b: Option<(~[u8], ~[u8])>
+}
+
+/// The differences between `a` and `b`, which must be sorted by key.
+pub fn diff<A: Iterator<(~[u8], ~[u8])>,
+            B: Iterator<(~[u8], ~[u8])>>(a: A, b: B) -> DiffIterator<A, B> {
+    let mut a = a;
+    let mut b = b;
+    let next_a = a.next();
+    let next_b = b.next();
+    DiffIterator {
+        a: a,
+        b: b,
+        next_a: next_a,
+        next_b: next_b
+    }
+}
+
+impl<A, B> DiffIterator<A, B> {
+    /// The two streams being compared.
+    pub fn streams<'a>(&'a self) -> (&'a A, &'a B) {
+        (&self.a, &self.b)
+    }
+}
+
+impl<A: Iterator<(~[u8], ~[u8])>,
+     B: Iterator<(~[u8], ~[u8])>> Iterator<Diff> for DiffIterator<A, B> {
+    fn next(&mut self) -> Option<Diff> {
+        loop {
+            let order = match (&self.next_a, &self.next_b) {
+                (&None, &None) => return None,
+                (&Some(_), &None) => Less,
+                (&None, &Some(_)) => Greater,
+                (&Some((ref key_a, _)), &Some((ref key_b, _))) => key_a.cmp(key_b)
+            };
+            match order {
+                Less => {
+                    let (key, value) = self.next_a.take_unwrap();
+                    self.next_a = self.a.next();
+                    return Some(OnlyInA(key, value));
+                },
+                Greater => {
+                    let (key, value) = self.next_b.take_unwrap();
+                    self.next_b = self.b.next();
+                    return Some(OnlyInB(key, value));
+                },
+                Equal => {
+                    let (key, value_a) = self.next_a.take_unwrap();
+                    let (_, value_b) = self.next_b.take_unwrap();
+                    self.next_a = self.a.next();
+                    self.next_b = self.b.next();
+                    if value_a != value_b {
+                        return Some(Changed(key, value_a, value_b));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Logical dumps.
+//!
+//! The binary format starts with a header: the magic bytes `LDBDUMP`, a
+//! format version byte, a flags byte, the entry count as a big-endian u64
+//! and, if flag 1 is set, the key range `[start, end)` dumped as two u32
+//! length-prefixed keys; the header ends with its CRC-32C. Each entry
+//! follows as a u32 length-prefixed key and value and the CRC-32C of both,
+//! all integers big-endian. The text formats hold one entry per line, as a
+//! JSON object or as a comma-separated pair, with keys and values in hex or
+//! base64; they have no header.
+//!
+//! Dumps are read from a snapshot. `dump` and `load` return I/O errors,
+//! and `load` a truncated binary dump, as errors rather than raising them
+//! through the `io_error` condition; `dump` also fails if reading the
+//! snapshot does.
+
+use std::cell::Cell;
+use std::cmp;
+use std::io::{Reader, Writer, io_error, EndOfFile};
+use std::io::buffered::BufferedReader;
+
+use extra::base64::{FromBase64, ToBase64, STANDARD};
+use extra::hex::{FromHex, ToHex};
+
+use super::{DB, DBIterator, RangeIterator, BatchWriter, BULK_BATCH_BYTES, error};
+use super::coding::{put_be32, put_be64};
+use super::crc32c;
+use super::cleveldb::leveldb_iter_destroy;
+
+static MAGIC: &'static [u8] = bytes!("LDBDUMP");
+static VERSION: u8 = 1;
+static FLAG_RANGE: u8 = 1;
+// Lengths read from a dump are read in chunks of this size, so that a
+// corrupt length cannot allocate more than the dump holds.
+static READ_CHUNK: uint = 64 * 1024;
+
+#[deriving(Eq, Clone)]
+pub enum DumpFormat {
+    Binary,
+    /// `{"key":"<base64>","value":"<base64>"}` per line
+    JsonLines,
+    /// `<hex key>,<hex value>` per line
+    HexCsv,
+    /// `<base64 key>,<base64 value>` per line
+    Base64Csv
+}
+
+fn encode_entry(key: &[u8], value: &[u8]) -> ~[u8] {
+    let mut buf = ~[];
+    put_be32(&mut buf, key.len() as u32);
+    buf.push_all(key);
+    put_be32(&mut buf, value.len() as u32);
+    buf.push_all(value);
+    let crc = crc32c::value(buf);
+    put_be32(&mut buf, crc);
+    buf
+}
+
+// Read `len` bytes, or fewer at the end of the input.
+fn read_chunked(reader: &mut Reader, len: uint) -> ~[u8] {
+    let mut bytes = ~[];
+    while bytes.len() < len {
+        let chunk = reader.read_bytes(cmp::min(len - bytes.len(), READ_CHUNK));
+        if chunk.is_empty() {
+            break;
+        }
+        bytes.push_all(chunk);
+    }
+    bytes
+}
+
+// The error of a finished scan, if any, after destroying its iterator.
+fn finish(it: RangeIterator) -> Option<error> {
+    let err = it.get_error();
+    unsafe {
+        leveldb_iter_destroy(it.iter.iter);
+    }
+    err
+}
+
+// The snapshot entries in `range`, or all of them.
+fn range_iter(iter: DBIterator, range: Option<(&[u8], &[u8])>) -> RangeIterator {
+    let mut iter = iter;
+    match range {
+        Some((start, _)) => iter.seek(start),
+        None => {}
+    }
+    RangeIterator {
+        iter: iter,
+        end: range.map(|(_, end)| end.to_owned())
+    }
+}
+
+impl DB {
+    /// Write the entries in `range`, or all entries, to `writer` in
+    /// `format`. Returns the number of entries written.
+    pub fn dump(&self, writer: &mut Writer, format: DumpFormat,
+                range: Option<(&[u8], &[u8])>) -> Result<u64, error> {
+        let failed = Cell::new(false);
+        let mut io_err = None;
+        let res = io_error::cond.trap(|e| {
+            failed.set(true);
+            if io_err.is_none() {
+                io_err = Some(e);
+            }
+        }).inside(|| self.dump_entries(writer, format, range, &failed));
+        match io_err {
+            Some(e) => Err(format!("writing dump: {}", e.desc)),
+            None => res
+        }
+    }
+
+    // Stops once `failed` is set by a write error.
+    fn dump_entries(&self, writer: &mut Writer, format: DumpFormat,
+                    range: Option<(&[u8], &[u8])>, failed: &Cell<bool>) -> Result<u64, error> {
+        let snapshot = self.snapshot();
+        let mut count = 0u64;
+        if format == Binary {
+            let mut it = range_iter(snapshot.iter([]), range);
+            for _ in it.by_ref() {
+                count += 1;
+            }
+            match finish(it) {
+                Some(err) => return Err(err),
+                None => {}
+            }
+            let mut header = MAGIC.to_owned();
+            header.push(VERSION);
+            header.push(if range.is_some() { FLAG_RANGE } else { 0 });
+            put_be64(&mut header, count);
+            match range {
+                Some((start, end)) => {
+                    put_be32(&mut header, start.len() as u32);
+                    header.push_all(start);
+                    put_be32(&mut header, end.len() as u32);
+                    header.push_all(end);
+                },
+                None => {}
+            }
+            let crc = crc32c::value(header);
+            put_be32(&mut header, crc);
+            writer.write(header);
+            let mut it = range_iter(snapshot.iter([]), range);
+            for (key, value) in it.by_ref() {
+                if failed.get() {
+                    break;
+                }
+                writer.write(encode_entry(key, value));
+            }
+            return match finish(it) {
+                Some(err) => Err(err),
+                None => Ok(count)
+            };
+        }
+
+        let mut it = range_iter(snapshot.iter([]), range);
+        for (key, value) in it.by_ref() {
+            if failed.get() {
+                break;
+            }
+            let line = match format {
+                JsonLines => format!("\\{\"key\":\"{}\",\"value\":\"{}\"\\}",
+                                     key.to_base64(STANDARD), value.to_base64(STANDARD)),
+                HexCsv => format!("{},{}", key.to_hex(), value.to_hex()),
+                _ => format!("{},{}", key.to_base64(STANDARD), value.to_base64(STANDARD))
+            };
+            writer.write_line(line);
+            count += 1;
+        }
+        match finish(it) {
+            Some(err) => Err(err),
+            None => Ok(count)
+        }
+    }
+
+    /// Write the entries read from `reader` in `format`, verifying their
+    /// checksums, and return the number of entries loaded.
+    pub fn load(&self, reader: &mut Reader, format: DumpFormat) -> Result<u64, error> {
+        let mut loader = Loader {
+            writer: BatchWriter::new(self, BULK_BATCH_BYTES),
+            count: 0
+        };
+        let mut io_err = None;
+        let res = io_error::cond.trap(|e| if io_err.is_none() { io_err = Some(e) }).inside(|| {
+            match format {
+                Binary => loader.load_binary(reader),
+                _ => loader.load_text(reader, format)
+            }
+        });
+        let res = match io_err {
+            // Text dumps end at the end of the input.
+            Some(ref e) if e.kind == EndOfFile && format != Binary => res,
+            Some(ref e) if e.kind == EndOfFile => Err(~"truncated dump"),
+            Some(ref e) => Err(format!("reading dump: {}", e.desc)),
+            None => res
+        };
+        match res {
+            Ok(_) => match loader.writer.flush() {
+                Ok(_) => Ok(loader.count),
+                Err(err) => Err(err)
+            },
+            Err(err) => Err(err)
+        }
+    }
+}
+
+struct Loader<'r> {
+    writer: BatchWriter<'r>,
+    count: u64
+}
+
+impl<'r> Loader<'r> {
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(), error> {
+        self.count += 1;
+        match self.writer.put(key, value) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn load_binary(&mut self, reader: &mut Reader) -> Result<(), error> {
+        let mut header = read_chunked(reader, MAGIC.len() + 10);
+        if header.len() < MAGIC.len() + 10 || header.slice_to(MAGIC.len()) != MAGIC {
+            return Err(~"not a dump");
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(format!("unsupported dump version {}", header[MAGIC.len()]));
+        }
+        let flags = header[MAGIC.len() + 1];
+        let expected = {
+            let mut n = 0u64;
+            for &b in header.slice_from(MAGIC.len() + 2).iter() {
+                n = (n << 8) | (b as u64);
+            }
+            n
+        };
+        if flags & FLAG_RANGE != 0 {
+            for _ in range(0, 2) {
+                let len = reader.read_be_u32();
+                put_be32(&mut header, len);
+                header.push_all(read_chunked(reader, len as uint));
+            }
+        }
+        if reader.read_be_u32() != crc32c::value(header) {
+            return Err(~"dump header checksum mismatch");
+        }
+
+        for i in range(0, expected) {
+            // After the end of the input, reads come back short or zero
+            // and the checksum fails; `load` reports the truncation.
+            let key_len = reader.read_be_u32();
+            let key = read_chunked(reader, key_len as uint);
+            let value_len = reader.read_be_u32();
+            let value = read_chunked(reader, value_len as uint);
+            let crc = reader.read_be_u32();
+            let mut entry = encode_entry(key, value);
+            entry.truncate(entry.len() - 4);
+            if crc != crc32c::value(entry) {
+                return Err(format!("checksum mismatch in entry {}", i));
+            }
+            match self.add(key, value) {
+                Ok(_) => {},
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    fn load_text(&mut self, reader: &mut Reader, format: DumpFormat) -> Result<(), error> {
+        let mut reader = BufferedReader::new(reader);
+        let mut line_no = 0u;
+        loop {
+            let line = match reader.read_line() {
+                Some(line) => line,
+                None => return Ok(())
+            };
+            line_no += 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: ~[&str] = match format {
+                JsonLines => {
+                    // {"key":"...","value":"..."} splits on quotes into
+                    // {, key, :, <key>, ",", value, :, <value>, }
+                    let parts: ~[&str] = line.split('"').collect();
+                    if parts.len() != 9 || parts[1] != "key" || parts[5] != "value" {
+                        return Err(format!("malformed line {}", line_no));
+                    }
+                    ~[parts[3], parts[7]]
+                },
+                _ => line.split(',').collect()
+            };
+            if fields.len() != 2 {
+                return Err(format!("malformed line {}", line_no));
+            }
+            let decoded: ~[Result<~[u8], ~str>] = fields.iter().map(|field| {
+                match format {
+                    HexCsv => field.from_hex(),
+                    _ => field.from_base64()
+                }
+            }).collect();
+            match decoded {
+                [Ok(ref key), Ok(ref value)] => match self.add(*key, *value) {
+                    Ok(_) => {},
+                    Err(err) => return Err(err)
+                },
+                _ => return Err(format!("bad encoding on line {}", line_no))
+            }
+        }
+    }
+}
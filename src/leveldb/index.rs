@@ -0,0 +1,242 @@
+//! Secondary indexes.
+//!
+//! An `Index` derives index keys from each record. Once an index is added
+//! to a `DB`, every `put`, `delete` and `write` made through any handle
+//! on it also updates the index entries of the keys it writes, in the same
+//! atomic batch: the old value of each key is read to remove its stale
+//! entries. An index entry is a key under a reserved prefix encoding the
+//! index name, the index key and the primary key, with an empty value.
+
+use super::{DB, Snapshot, WriteBatch, BULK_BATCH_BYTES, error, is_internal_key};
+use super::cleveldb::leveldb_iter_destroy;
+use super::keys;
+use super::keys::{Bytes, Str};
+use super::options::{ReadOption, WriteOption};
+
+static ENTRY_PREFIX: &'static [u8] = bytes!("\x00idx\x00");
+
+/// Computes the index keys of a record.
+///
+/// Called on every write, while the database's write hooks are locked, so
+/// it must not use the database.
+pub trait Extractor {
+    fn extract(&self, key: &[u8], value: &[u8]) -> ~[~[u8]];
+}
+
+pub struct Index {
+    priv name: ~str,
+    priv extract: ~Extractor:Send
+}
+
+impl Index {
+    pub fn new(name: &str, extract: ~Extractor:Send) -> Index {
+        Index {
+            name: name.to_owned(),
+            extract: extract
+        }
+    }
+
+    pub fn name<'a>(&'a self) -> &'a str {
+        self.name.as_slice()
+    }
+
+    /// The keys of the index entries for record `(key, value)`.
+    pub fn entry_keys(&self, key: &[u8], value: &[u8]) -> ~[~[u8]] {
+        self.extract.extract(key, value).iter().map(|index_key| {
+            entry_key(self.name, *index_key, key)
+        }).collect()
+    }
+}
+
+/// Whether `key` is an index entry.
+pub fn is_entry_key(key: &[u8]) -> bool {
+    key.starts_with(ENTRY_PREFIX)
+}
+
+fn entry_key(name: &str, index_key: &[u8], key: &[u8]) -> ~[u8] {
+    let mut entry = ENTRY_PREFIX.to_owned();
+    entry.push_all(keys::encode([Str(name.to_owned()), Bytes(index_key.to_owned()),
+                                 Bytes(key.to_owned())]));
+    entry
+}
+
+impl DB {
+    /// Maintain `index` on every write from now on. Existing records are
+    /// not indexed; see `rebuild_index`.
+    pub fn add_index(&mut self, index: Index) {
+        let mut index = Some(index);
+        self.with_hooks(|hooks| hooks.indexes.push(index.take_unwrap()));
+    }
+
+    // Run `f` on the index named `name`; `f` must not reach the database.
+    fn with_index<T>(&self, name: &str, f: |&Index| -> T) -> Result<T, error> {
+        self.with_hooks(|hooks| {
+            match hooks.indexes.iter().find(|index| index.name.as_slice() == name) {
+                Some(index) => Ok(f(index)),
+                None => Err(format!("no index named {}", name))
+            }
+        })
+    }
+
+    /// The primary keys of the records whose index `name` contains
+    /// `index_key`, in key order.
+    pub fn index_keys(&self, name: &str, index_key: &[u8],
+                      options: &[ReadOption]) -> Result<~[~[u8]], error> {
+        self.index_keys_in(&self.snapshot(), name, index_key, options)
+    }
+
+    fn index_keys_in(&self, snapshot: &Snapshot, name: &str, index_key: &[u8],
+                     options: &[ReadOption]) -> Result<~[~[u8]], error> {
+        match self.with_index(name, |_| ()) {
+            Ok(_) => {},
+            Err(err) => return Err(err)
+        }
+        let (start, end) = keys::prefix_range([Str(name.to_owned()), Bytes(index_key.to_owned())]);
+        let mut prefix = ENTRY_PREFIX.to_owned();
+        prefix.push_all(start);
+        let mut limit = ENTRY_PREFIX.to_owned();
+        limit.push_all(end);
+
+        let mut primary_keys = ~[];
+        let mut res = Ok(());
+        let mut it = snapshot.iter(options);
+        it.seek(prefix);
+        while it.is_valid() {
+            let entry = it.key();
+            if entry >= limit {
+                break;
+            }
+            let parts = match keys::decode(entry.slice_from(ENTRY_PREFIX.len())) {
+                Ok(parts) => parts,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            };
+            match parts.last() {
+                Some(&Bytes(ref key)) if parts.len() == 3 => primary_keys.push(key.clone()),
+                _ => {
+                    res = Err(~"corrupt index entry");
+                    break;
+                }
+            }
+            it.next();
+        }
+        if res.is_ok() {
+            res = match it.get_error() {
+                Some(err) => Err(err),
+                None => Ok(())
+            };
+        }
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        match res {
+            Ok(_) => Ok(primary_keys),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// The records whose index `name` contains `index_key`. The index
+    /// entries and the records are read from one snapshot, so every
+    /// record returned matches `index_key`.
+    pub fn index_lookup(&self, name: &str, index_key: &[u8],
+                        options: &[ReadOption]) -> Result<~[(~[u8], ~[u8])], error> {
+        let snapshot = self.snapshot();
+        let primary_keys = match self.index_keys_in(&snapshot, name, index_key, options) {
+            Ok(keys) => keys,
+            Err(err) => return Err(err)
+        };
+        let key_slices: ~[&[u8]] = primary_keys.iter().map(|key| key.as_slice()).collect();
+        let values = match snapshot.multi_get(key_slices, options) {
+            Ok(values) => values,
+            Err(err) => return Err(err)
+        };
+        let mut records = ~[];
+        for (key, value) in primary_keys.move_iter().zip(values.move_iter()) {
+            match value {
+                Some(value) => records.push((key, value)),
+                // Entries are written atomically with their records, so
+                // this only skips entries written by other means.
+                None => {}
+            }
+        }
+        Ok(records)
+    }
+
+    /// Write the index entries of every record matching `is_record`,
+    /// e.g. after adding an index to a database holding data. Returns the
+    /// number of entries written.
+    ///
+    /// Records are processed in chunks of bounded size. The entries of a
+    /// chunk are computed from the records' current values and written
+    /// under the write lock, like any other write, so concurrent writes
+    /// leave no stale entries.
+    pub fn rebuild_index(&self, name: &str, is_record: |&[u8]| -> bool,
+                         options: &[WriteOption]) -> Result<uint, error> {
+        match self.with_index(name, |_| ()) {
+            Ok(_) => {},
+            Err(err) => return Err(err)
+        }
+        let mut count = 0u;
+        let mut from = ~[];
+        loop {
+            let mut keys = ~[];
+            let mut pending = 0u;
+            let mut it = self.iter([]);
+            it.seek(from);
+            while pending < BULK_BATCH_BYTES && it.is_valid() {
+                let key = it.key();
+                if !is_internal_key(key) && is_record(key) {
+                    pending += key.len();
+                    keys.push(key);
+                }
+                it.next();
+            }
+            let err = it.get_error();
+            let next = if it.is_valid() { Some(it.key()) } else { None };
+            unsafe {
+                leveldb_iter_destroy(it.iter);
+            }
+            match err {
+                Some(err) => return Err(err),
+                None => {}
+            }
+            match self.write_lock.lock(|| self.write_index_entries(name, keys, options)) {
+                Ok(n) => count += n,
+                Err(err) => return Err(err)
+            }
+            match next {
+                Some(next) => from = next,
+                None => return Ok(count)
+            }
+        }
+    }
+
+    // Write the index entries of the records among `keys` that still
+    // exist. Must run under the write lock.
+    fn write_index_entries(&self, name: &str, keys: &[~[u8]],
+                           options: &[WriteOption]) -> Result<uint, error> {
+        let mut batch = WriteBatch::new();
+        let mut count = 0u;
+        for key in keys.iter() {
+            let value = match self.get_opt(*key, []) {
+                Ok(Some(value)) => value,
+                Ok(None) => continue,
+                Err(err) => return Err(err)
+            };
+            let entries = match self.with_index(name, |index| index.entry_keys(*key, value)) {
+                Ok(entries) => entries,
+                Err(err) => return Err(err)
+            };
+            for entry in entries.iter() {
+                batch.put(*entry, []);
+                count += 1;
+            }
+        }
+        match self.write_unlocked(&batch, options) {
+            Ok(_) => Ok(count),
+            Err(err) => Err(err)
+        }
+    }
+}
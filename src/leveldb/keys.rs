@@ -0,0 +1,177 @@
+//! Order-preserving encoding of composite keys.
+//!
+//! LevelDB compares keys bytewise. The encoding below turns a tuple of
+//! integers, floats, strings and byte strings into bytes whose bytewise
+//! order matches the logical order of the tuple, compared element by
+//! element. Every element is self-delimiting, so the encoding of a tuple
+//! prefix is a byte prefix of the encoding of the full tuple, and
+//! `prefix_range` gives bounds usable with `DBIterator::seek`.
+
+use std::cast::transmute;
+use std::str;
+
+use super::error;
+use super::coding::{put_be64, get_be64};
+
+static TAG_UINT: u8 = 0x10;
+static TAG_INT: u8 = 0x20;
+static TAG_FLOAT: u8 = 0x30;
+static TAG_STR: u8 = 0x40;
+static TAG_BYTES: u8 = 0x50;
+
+static ESCAPE: u8 = 0x00;
+static ESCAPED_NUL: u8 = 0xff;
+static TERMINATOR: u8 = 0x01;
+
+/// One element of a composite key.
+///
+/// Elements of different kinds order by kind first, in the order the
+/// variants are declared.
+#[deriving(Eq, Clone)]
+pub enum KeyPart {
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Str(~str),
+    Bytes(~[u8]),
+}
+
+/// Encode a tuple of key parts into memcomparable bytes.
+pub fn encode(parts: &[KeyPart]) -> ~[u8] {
+    let mut buf = ~[];
+    for part in parts.iter() {
+        encode_part(&mut buf, part);
+    }
+    buf
+}
+
+/// Append the encoding of a single key part to `buf`.
+pub fn encode_part(buf: &mut ~[u8], part: &KeyPart) {
+    match *part {
+        UInt(n) => {
+            buf.push(TAG_UINT);
+            put_be64(buf, n);
+        },
+        Int(n) => {
+            buf.push(TAG_INT);
+            put_be64(buf, (n as u64) ^ (1u64 << 63));
+        },
+        Float(f) => {
+            buf.push(TAG_FLOAT);
+            let bits: u64 = unsafe { transmute(f) };
+            if bits & (1u64 << 63) != 0 {
+                put_be64(buf, !bits);
+            } else {
+                put_be64(buf, bits ^ (1u64 << 63));
+            }
+        },
+        Str(ref s) => {
+            buf.push(TAG_STR);
+            put_escaped(buf, s.as_bytes());
+        },
+        Bytes(ref b) => {
+            buf.push(TAG_BYTES);
+            put_escaped(buf, *b);
+        }
+    }
+}
+
+/// Decode bytes produced by `encode` back into key parts.
+pub fn decode(key: &[u8]) -> Result<~[KeyPart], error> {
+    let mut parts = ~[];
+    let mut pos = 0u;
+    while pos < key.len() {
+        let tag = key[pos];
+        pos += 1;
+        if tag == TAG_UINT || tag == TAG_INT || tag == TAG_FLOAT {
+            if pos + 8 > key.len() {
+                return Err(format!("truncated key part at offset {}", pos - 1));
+            }
+            let n = get_be64(key.slice(pos, pos + 8));
+            pos += 8;
+            if tag == TAG_UINT {
+                parts.push(UInt(n));
+            } else if tag == TAG_INT {
+                parts.push(Int((n ^ (1u64 << 63)) as i64));
+            } else {
+                let bits = if n & (1u64 << 63) != 0 { n ^ (1u64 << 63) } else { !n };
+                parts.push(Float(unsafe { transmute(bits) }));
+            }
+        } else if tag == TAG_STR || tag == TAG_BYTES {
+            let (bytes, next) = match get_escaped(key, pos) {
+                Some(res) => res,
+                None => return Err(format!("unterminated key part at offset {}", pos - 1))
+            };
+            pos = next;
+            if tag == TAG_BYTES {
+                parts.push(Bytes(bytes));
+            } else {
+                match str::from_utf8_owned_opt(bytes) {
+                    Some(s) => parts.push(Str(s)),
+                    None => return Err(~"invalid utf-8 in string key part")
+                }
+            }
+        } else {
+            return Err(format!("unknown key part tag {} at offset {}", tag, pos - 1));
+        }
+    }
+    Ok(parts)
+}
+
+/// Bounds `[start, end)` covering every key whose leading parts equal
+/// `prefix`, including the key made of `prefix` alone.
+///
+/// Seek a `DBIterator` to `start` and stop once a key is `>= end`.
+pub fn prefix_range(prefix: &[KeyPart]) -> (~[u8], ~[u8]) {
+    let start = encode(prefix);
+    let mut end = start.clone();
+    // Every encoded part starts with a tag below 0xff.
+    end.push(0xff);
+    (start, end)
+}
+
+/// The smallest key greater than every key starting with `prefix`, or
+/// `None` if no such key exists (`prefix` is empty or all 0xff).
+pub fn prefix_successor(prefix: &[u8]) -> Option<~[u8]> {
+    let mut end = prefix.to_owned();
+    while end.len() > 0 {
+        let last = end.len() - 1;
+        if end[last] != 0xff {
+            end[last] += 1;
+            return Some(end);
+        }
+        end.pop();
+    }
+    None
+}
+
+fn put_escaped(buf: &mut ~[u8], bytes: &[u8]) {
+    for &b in bytes.iter() {
+        buf.push(b);
+        if b == ESCAPE {
+            buf.push(ESCAPED_NUL);
+        }
+    }
+    buf.push(ESCAPE);
+    buf.push(TERMINATOR);
+}
+
+fn get_escaped(key: &[u8], start: uint) -> Option<(~[u8], uint)> {
+    let mut bytes = ~[];
+    let mut pos = start;
+    while pos + 1 < key.len() {
+        let b = key[pos];
+        if b != ESCAPE {
+            bytes.push(b);
+            pos += 1;
+        } else if key[pos + 1] == ESCAPED_NUL {
+            bytes.push(ESCAPE);
+            pos += 2;
+        } else if key[pos + 1] == TERMINATOR {
+            return Some((bytes, pos + 2));
+        } else {
+            return None;
+        }
+    }
+    None
+}
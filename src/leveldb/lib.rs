@@ -11,15 +11,62 @@
 #[feature(macro_rules)];
 #[feature(globs)];
 
+extern mod extra;
+
+use std::cast::transmute;
+use std::cmp;
+use std::hashmap::HashMap;
+use std::io::{Reader, io_error};
+use std::io::fs::File;
+use std::ptr;
 use std::ptr::{mut_null, to_mut_unsafe_ptr, is_null, is_not_null};
 use std::str::raw::from_c_str;
-use std::libc::{c_char, size_t};
+use std::libc::{c_char, c_int, c_void, size_t};
+use std::vec;
 use std::vec::raw::from_buf_raw;
 
+use extra::arc::MutexArc;
+use extra::sync::Mutex;
+
 use self::cleveldb::*;
 use self::options::*;
+use self::locks::{LockTable, DEFAULT_STRIPES};
+use self::merge::{MergeOperator, MergeStrategy, Lazy, operand_base, operand_prefix};
+use self::coding::{encode_batch, encode_u64};
+use self::index::Index;
+use self::subscribe::{Subscribers, DEFAULT_CAPACITY};
+
+pub use self::subscribe::{Change, Put, Delete, Subscription};
 
 mod cleveldb;
+mod locks;
+mod subscribe;
+mod coding;
+
+pub mod keys;
+#[cfg(codec)]
+pub mod codec;
+pub mod namespace;
+pub mod transaction;
+pub mod merge;
+pub mod counter;
+pub mod cas;
+pub mod multi_get;
+pub mod delete_range;
+pub mod ttl;
+pub mod index;
+pub mod replication;
+pub mod backup;
+pub mod crc32c;
+pub mod dump;
+pub mod snappy;
+pub mod table;
+pub mod log;
+pub mod manifest;
+pub mod verify;
+pub mod diff;
+pub mod merkle;
+pub mod par_scan;
 
 pub mod options {
     pub enum OpenOption {
@@ -37,13 +84,175 @@ pub mod options {
         FILL_CACHE,
         // USE_SNAPSHOT(),
     }
+
+    pub enum DeleteRangeOption {
+        /// Flush a batch once its keys add up to this many bytes.
+        BATCH_BYTES(uint),
+        /// Compact the range once its keys are deleted.
+        COMPACT,
+    }
+}
+
+struct SharedHandle {
+    db: uint,
+    write_lock: Mutex,
+    key_locks: LockTable,
+    hooks: MutexArc<Hooks>,
+    merge_strategy: MutexArc<Option<MergeStrategy>>,
+    merge_seq: MutexArc<u64>,
+    subscribers: Subscribers
+}
+
+impl SharedHandle {
+    fn open(self) -> DB {
+        let SharedHandle { db, write_lock, key_locks, hooks, merge_strategy, merge_seq,
+                           subscribers } = self;
+        DB {
+            db: db as *mut leveldb_t,
+            write_lock: write_lock,
+            key_locks: key_locks,
+            hooks: hooks,
+            merge_strategy: merge_strategy,
+            merge_seq: merge_seq,
+            subscribers: subscribers
+        }
+    }
+}
+
+// What writes do besides the LevelDB write, shared by all handles on a
+// database.
+struct Hooks {
+    merge: Option<(~MergeOperator:Send, MergeStrategy)>,
+    indexes: ~[Index],
+    // Sequence number of the last change log entry, if the log is enabled.
+    change_log: Option<u64>
+}
+
+impl Hooks {
+    fn is_lazy_merge(&self) -> bool {
+        match self.merge {
+            Some((_, Lazy)) => true,
+            _ => false
+        }
+    }
+
+    // Whether writes must be expanded by `DB::expand_batch`.
+    fn expand_writes(&self) -> bool {
+        !self.indexes.is_empty() || self.change_log.is_some() || self.is_lazy_merge()
+    }
+}
+
+/// A set of updates applied atomically by `DB::write`
+pub struct WriteBatch {
+    priv batch: *mut leveldb_writebatch_t
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        unsafe {
+            WriteBatch {
+                batch: leveldb_writebatch_create()
+            }
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        unsafe {
+            let (c_key, c_key_len) = to_c_str(key);
+            let (c_val, c_val_len) = to_c_str(value);
+            leveldb_writebatch_put(self.batch,
+                c_key, c_key_len,
+                c_val, c_val_len);
+        }
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        unsafe {
+            let (c_key, c_key_len) = to_c_str(key);
+            leveldb_writebatch_delete(self.batch, c_key, c_key_len);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        unsafe {
+            leveldb_writebatch_clear(self.batch);
+        }
+    }
+
+    /// Pass the updates of this batch to `visitor`, in order.
+    pub fn iterate(&self, visitor: &mut WriteBatchVisitor) {
+        unsafe {
+            let mut visitor = visitor;
+            leveldb_writebatch_iterate(self.batch,
+                to_mut_unsafe_ptr(&mut visitor) as *mut c_void,
+                visit_put, visit_delete);
+        }
+    }
+
+    /// The updates of this batch, in order; `None` stands for a delete.
+    pub fn updates(&self) -> ~[(~[u8], Option<~[u8]>)] {
+        let mut updates = ~[];
+        self.iterate(&mut updates as &mut WriteBatchVisitor);
+        updates
+    }
 }
 
-pub type WriteBatch<'r> = &'r [(&'r [u8], &'r [u8])];
+/// Receives the updates of a `WriteBatch`
+pub trait WriteBatchVisitor {
+    fn put(&mut self, key: &[u8], value: &[u8]);
+    fn delete(&mut self, key: &[u8]);
+}
+
+impl WriteBatchVisitor for ~[(~[u8], Option<~[u8]>)] {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.push((key.to_owned(), Some(value.to_owned())));
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.push((key.to_owned(), None));
+    }
+}
+
+extern "C" fn visit_put(state: *mut c_void, key: *c_char, key_len: size_t,
+                        val: *c_char, val_len: size_t) {
+    unsafe {
+        let visitor: &mut &mut WriteBatchVisitor = transmute(state);
+        let key = from_buf_raw(key as *u8, key_len as uint);
+        let val = from_buf_raw(val as *u8, val_len as uint);
+        visitor.put(key, val);
+    }
+}
+
+extern "C" fn visit_delete(state: *mut c_void, key: *c_char, key_len: size_t) {
+    unsafe {
+        let visitor: &mut &mut WriteBatchVisitor = transmute(state);
+        let key = from_buf_raw(key as *u8, key_len as uint);
+        visitor.delete(key);
+    }
+}
+
+impl Drop for WriteBatch {
+    fn drop(&mut self) {
+        unsafe {
+            leveldb_writebatch_destroy(self.batch);
+        }
+    }
+}
 
 /// A database object
 pub struct DB {
-    db: *mut leveldb_t
+    db: *mut leveldb_t,
+    // Held around every write, so that code validating reads before
+    // writing sees no interleaved writes.
+    priv write_lock: Mutex,
+    priv key_locks: LockTable,
+    priv hooks: MutexArc<Hooks>,
+    // The strategy of the merge operator in `hooks`, kept apart so that
+    // reads need not wait for the hooks.
+    priv merge_strategy: MutexArc<Option<MergeStrategy>>,
+    // Sequence number of the last lazily stored merge operand.
+    priv merge_seq: MutexArc<u64>,
+    priv subscribers: Subscribers
 }
 
 pub type error = ~str;
@@ -99,13 +308,90 @@ fn to_c_read_options(options: &[ReadOption]) -> *leveldb_readoptions_t {
     }
 }
 
-fn to_c_str(s: &[u8]) -> (*c_char, size_t) {
+fn to_c_snapshot_read_options(options: &[ReadOption],
+                              snapshot: *leveldb_snapshot_t) -> *leveldb_readoptions_t {
     unsafe {
-        let c_str = s.to_c_str();
-        let len = c_str.len();
-        (c_str.unwrap(), len as size_t)
+        let c_options = to_c_read_options(options) as *mut leveldb_readoptions_t;
+        leveldb_readoptions_set_snapshot(c_options, snapshot);
+        c_options as *leveldb_readoptions_t
     }
-} 
+}
+
+// Keys and values are length-delimited, so they may contain NUL bytes
+// and are passed to LevelDB without copying.
+fn to_c_str(s: &[u8]) -> (*c_char, size_t) {
+    (s.as_ptr() as *c_char, s.len() as size_t)
+}
+
+// The contents of a file, with I/O errors returned rather than raised.
+fn read_file(path: &Path) -> Result<~[u8], error> {
+    let mut err = None;
+    let bytes = io_error::cond.trap(|e| err = Some(e.desc.to_owned())).inside(|| {
+        File::open(path).map(|mut file| file.read_to_end())
+    });
+    match (bytes, err) {
+        (_, Some(err)) => Err(format!("{}: {}", path.display(), err)),
+        (Some(bytes), None) => Ok(bytes),
+        (None, None) => Err(format!("{}: cannot open", path.display()))
+    }
+}
+
+// Whether `key` is one the wrapper keeps for its own bookkeeping rather
+// than a record: an index or expiry entry, a change log key, a lazy merge
+// operand or sequence number or the namespace registry.
+fn is_internal_key(key: &[u8]) -> bool {
+    index::is_entry_key(key) || ttl::is_expiry_key(key) || replication::is_log_key(key)
+        || namespace::is_registry_key(key) || operand_base(key).is_some()
+        || merge::is_seq_key(key)
+}
+
+// Bisection steps when looking for a key splitting a range in half.
+static SPLIT_STEPS: uint = 8;
+
+// The key halfway between `a` and `b` read as big-endian fractions, or
+// `None` if there is no key strictly between them at this precision.
+fn midpoint(a: &[u8], b: Option<&[u8]>) -> Option<~[u8]> {
+    let len = match b {
+        Some(b) => cmp::max(a.len(), b.len()) + 1,
+        None => a.len() + 1
+    };
+    let digit = |key: &[u8], i: uint| if i < key.len() { key[i] as uint } else { 0 };
+    // Sum, least significant digit first, then halve from the top.
+    let mut sum = ~[];
+    let mut carry = 0u;
+    let mut i = len;
+    while i > 0 {
+        i -= 1;
+        let b_digit = match b {
+            Some(b) => digit(b, i),
+            None => 0xff
+        };
+        let s = digit(a, i) + b_digit + carry;
+        sum.push(s & 0xff);
+        carry = s >> 8;
+    }
+    let mut mid = ~[];
+    let mut rem = carry;
+    let mut i = sum.len();
+    while i > 0 {
+        i -= 1;
+        let cur = (rem << 8) | sum[i];
+        mid.push((cur / 2) as u8);
+        rem = cur % 2;
+    }
+    while mid.len() > 1 && mid.last() == Some(&0) {
+        mid.pop();
+    }
+    let below_end = match b {
+        Some(b) => mid.as_slice() < b,
+        None => true
+    };
+    if mid.as_slice() > a && below_end {
+        Some(mid)
+    } else {
+        None
+    }
+}
 
 impl DB {
     /// Open a database connection
@@ -119,10 +405,76 @@ impl DB {
             if is_null(c_db) {
                 return Err(from_c_str(err as *c_char));
             } else {
-                return Ok(~DB{
-                    db: c_db
-                });
+                return Ok(~DB::from_c_db(c_db));
+            }
+        }
+    }
+
+    /// Delete the database at `name` and everything in it.
+    pub fn destroy(name: &str, options: &[OpenOption]) -> Result<(), error> {
+        unsafe {
+            let c_options = to_c_open_options(options);
+            let mut err: *mut c_char = mut_null();
+            leveldb_destroy_db(c_options, name.to_c_str().unwrap(),
+                to_mut_unsafe_ptr(&mut err));
+            if is_not_null(err) {
+                return Err(from_c_str(err as *c_char));
+            }
+            Ok(())
+        }
+    }
+
+    /// Recover as much data as possible from a corrupted database.
+    pub fn repair(name: &str, options: &[OpenOption]) -> Result<(), error> {
+        unsafe {
+            let c_options = to_c_open_options(options);
+            let mut err: *mut c_char = mut_null();
+            leveldb_repair_db(c_options, name.to_c_str().unwrap(),
+                to_mut_unsafe_ptr(&mut err));
+            if is_not_null(err) {
+                return Err(from_c_str(err as *c_char));
             }
+            Ok(())
+        }
+    }
+
+    fn from_c_db(c_db: *mut leveldb_t) -> DB {
+        DB {
+            db: c_db,
+            write_lock: Mutex::new(),
+            key_locks: LockTable::new(DEFAULT_STRIPES),
+            hooks: MutexArc::new(Hooks {
+                merge: None,
+                indexes: ~[],
+                change_log: None
+            }),
+            merge_strategy: MutexArc::new(None),
+            merge_seq: MutexArc::new(0),
+            subscribers: Subscribers::new()
+        }
+    }
+
+    // A handle on this database that can be sent to another task. The
+    // handle shares the locks, merge operator, indexes, change log and
+    // subscribers, so its writes are the same as this handle's. It must
+    // not be used after this database is closed.
+    fn shared_handle(&self) -> SharedHandle {
+        SharedHandle {
+            db: self.db as uint,
+            write_lock: self.write_lock.clone(),
+            key_locks: self.key_locks.clone(),
+            hooks: self.hooks.clone(),
+            merge_strategy: self.merge_strategy.clone(),
+            merge_seq: self.merge_seq.clone(),
+            subscribers: self.subscribers.clone()
+        }
+    }
+
+    // Run `f` on the write hooks. `f` must not reach them again, through
+    // this or another handle, as the lock on them is not reentrant.
+    fn with_hooks<T>(&self, f: |&mut Hooks| -> T) -> T {
+        unsafe {
+            self.hooks.unsafe_access(f)
         }
     }
 
@@ -133,6 +485,15 @@ impl DB {
     }
 
     pub fn put(&self, key: &[u8], value: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        self.write_lock.lock(|| self.put_unlocked(key, value, options))
+    }
+
+    fn put_unlocked(&self, key: &[u8], value: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        if self.has_write_hooks() {
+            let mut batch = WriteBatch::new();
+            batch.put(key, value);
+            return self.write_unlocked(&batch, options);
+        }
         unsafe {
             let mut c_err: *mut c_char = mut_null();
             let (c_key, c_key_len) = to_c_str(key);
@@ -150,23 +511,148 @@ impl DB {
     }
 
     pub fn get(&self, key: &[u8], options: &[ReadOption]) -> Result<~[u8], error> {
+        match self.get_with(key, to_c_read_options(options), false) {
+            Ok(value) => Ok(value.unwrap_or(~[])),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Like `get`, but distinguishes a missing key (`None`) from an empty
+    /// value.
+    pub fn get_opt(&self, key: &[u8], options: &[ReadOption]) -> Result<Option<~[u8]>, error> {
+        self.get_with(key, to_c_read_options(options), false)
+    }
+
+    // Read `key` with `c_options`; `pinned` tells whether they read from a
+    // snapshot. Lazy merge operands are folded into the value, reading
+    // both from one snapshot so that a concurrent collapse cannot hide
+    // operands.
+    fn get_with(&self, key: &[u8], c_options: *leveldb_readoptions_t,
+                pinned: bool) -> Result<Option<~[u8]>, error> {
+        if !self.is_lazy_merge() {
+            return self.get_raw(key, c_options);
+        }
+        if pinned {
+            return match self.get_raw(key, c_options) {
+                Ok(value) => self.fold_merge_operands(key, value, c_options),
+                Err(err) => Err(err)
+            };
+        }
+        unsafe {
+            let c_snapshot = leveldb_create_snapshot(self.db);
+            let c_options = c_options as *mut leveldb_readoptions_t;
+            leveldb_readoptions_set_snapshot(c_options, c_snapshot);
+            let c_options = c_options as *leveldb_readoptions_t;
+            let res = match self.get_raw(key, c_options) {
+                Ok(value) => self.fold_merge_operands(key, value, c_options),
+                Err(err) => Err(err)
+            };
+            leveldb_readoptions_set_snapshot(c_options as *mut leveldb_readoptions_t, ptr::null());
+            leveldb_release_snapshot(self.db, c_snapshot);
+            res
+        }
+    }
+
+    fn is_lazy_merge(&self) -> bool {
+        self.merge_strategy() == Some(Lazy)
+    }
+
+    fn merge_strategy(&self) -> Option<MergeStrategy> {
+        unsafe {
+            self.merge_strategy.unsafe_access(|strategy| *strategy)
+        }
+    }
+
+    // Apply the merge operator, which must be set.
+    fn apply_merge_operator(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> ~[u8] {
+        self.with_hooks(|hooks| match hooks.merge {
+            Some((ref operator, _)) => operator.merge(key, existing, operand),
+            None => fail!("no merge operator set")
+        })
+    }
+
+    // Fold the merge operands stored for `key` into `base`, reading with
+    // `c_options`.
+    fn fold_merge_operands(&self, key: &[u8], base: Option<~[u8]>,
+                           c_options: *leveldb_readoptions_t) -> Result<Option<~[u8]>, error> {
+        let prefix = operand_prefix(key);
+        let mut value = base;
+        let mut it = self.iter_with(c_options);
+        it.seek(prefix);
+        while it.is_valid() {
+            let operand_key = it.key();
+            if !operand_key.starts_with(prefix) {
+                break;
+            }
+            let operand = it.value();
+            value = Some(self.apply_merge_operator(key, value.as_ref().map(|v| v.as_slice()), operand));
+            it.next();
+        }
+        let res = match it.get_error() {
+            Some(err) => Err(err),
+            None => Ok(value)
+        };
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        res
+    }
+
+    // The keys of the lazy merge operands currently stored for `key`.
+    fn merge_operand_keys(&self, key: &[u8]) -> Result<~[~[u8]], error> {
+        let prefix = operand_prefix(key);
+        let mut keys = ~[];
+        let mut it = self.iter([]);
+        it.seek(prefix);
+        while it.is_valid() {
+            let operand_key = it.key();
+            if !operand_key.starts_with(prefix) {
+                break;
+            }
+            keys.push(operand_key);
+            it.next();
+        }
+        let res = match it.get_error() {
+            Some(err) => Err(err),
+            None => Ok(keys)
+        };
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        res
+    }
+
+    fn get_raw(&self, key: &[u8], c_options: *leveldb_readoptions_t) -> Result<Option<~[u8]>, error> {
         unsafe {
             let mut c_err: *mut c_char = mut_null();
             let (c_key, c_key_len) = to_c_str(key);
             let mut c_value_len: size_t = 0;
-            let c_value = leveldb_get(self.db, to_c_read_options(options),
+            let c_value = leveldb_get(self.db, c_options,
                 c_key, c_key_len,
                 to_mut_unsafe_ptr(&mut c_value_len),
                 to_mut_unsafe_ptr(&mut c_err));
             if is_not_null(c_err) {
                 return Err(from_c_str(c_err as *c_char));
+            } else if is_null(c_value) {
+                return Ok(None);
             } else {
-                return Ok(from_buf_raw(c_value as *u8, c_value_len as uint));
+                let value = from_buf_raw(c_value as *u8, c_value_len as uint);
+                leveldb_free(c_value as *mut c_void);
+                return Ok(Some(value));
             }
         }
     }
 
     pub fn delete(&self, key: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        self.write_lock.lock(|| self.delete_unlocked(key, options))
+    }
+
+    fn delete_unlocked(&self, key: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        if self.has_write_hooks() {
+            let mut batch = WriteBatch::new();
+            batch.delete(key);
+            return self.write_unlocked(&batch, options);
+        }
         unsafe {
             let mut c_err: *mut c_char = mut_null();
             let (c_key, c_key_len) = to_c_str(key);
@@ -181,19 +667,163 @@ impl DB {
         }
     }
 
-    pub fn write(&self, write_batch: WriteBatch, options: &[WriteOption]) -> Result<(), error> {
-        unsafe {
-            let c_write_batch = leveldb_writebatch_create();
-            for &(key, value) in write_batch.iter() {
-                let (c_key, c_key_len) = to_c_str(key);
-                let (c_val, c_val_len) = to_c_str(value);
-                leveldb_writebatch_put(c_write_batch,
-                    c_key, c_key_len,
-                    c_val, c_val_len);
+    pub fn write(&self, write_batch: &WriteBatch, options: &[WriteOption]) -> Result<(), error> {
+        self.write_lock.lock(|| self.write_unlocked(write_batch, options))
+    }
+
+    // Whether writes need more than a plain LevelDB write.
+    fn has_write_hooks(&self) -> bool {
+        !self.subscribers.is_empty() || self.with_hooks(|hooks| hooks.expand_writes())
+    }
+
+    // The hooks are locked to expand the batch but not across the LevelDB
+    // write, which may sync. The write lock keeps the change log sequence
+    // from moving in between.
+    fn write_unlocked(&self, write_batch: &WriteBatch, options: &[WriteOption]) -> Result<(), error> {
+        self.write_unlocked_with(write_batch, options, false)
+    }
+
+    // Like `write`, for a batch whose values carry the expiry header of
+    // the `ttl` module, which subscribers are not shown.
+    fn write_ttl_values(&self, write_batch: &WriteBatch,
+                        options: &[WriteOption]) -> Result<(), error> {
+        self.write_lock.lock(|| self.write_unlocked_with(write_batch, options, true))
+    }
+
+    fn write_unlocked_with(&self, write_batch: &WriteBatch, options: &[WriteOption],
+                           ttl_values: bool) -> Result<(), error> {
+        let expanded = self.with_hooks(|hooks| {
+            if hooks.expand_writes() {
+                Some(self.expand_write(hooks, write_batch))
+            } else {
+                None
+            }
+        });
+        let res = match expanded {
+            Some(Ok((batch, log_seq))) => {
+                let res = self.write_raw(&batch, options);
+                if res.is_ok() && log_seq.is_some() {
+                    self.with_hooks(|hooks| hooks.change_log = log_seq);
+                }
+                res
+            },
+            Some(Err(err)) => Err(err),
+            None => self.write_raw(write_batch, options)
+        };
+        if res.is_ok() && !self.subscribers.is_empty() {
+            let mut updates = write_batch.updates();
+            if ttl_values {
+                updates = updates.move_iter().map(|(key, value)| {
+                    (key, value.map(ttl::strip_header))
+                }).collect();
+            }
+            self.subscribers.publish(updates);
+        }
+        res
+    }
+
+    // `write_batch` together with the index entry updates, operand
+    // deletions and change log entry it implies, and the sequence number
+    // of that entry.
+    fn expand_write(&self, hooks: &Hooks,
+                    write_batch: &WriteBatch) -> Result<(WriteBatch, Option<u64>), error> {
+        let mut batch = match self.expand_batch(hooks, write_batch) {
+            Ok(batch) => batch,
+            Err(err) => return Err(err)
+        };
+        match hooks.change_log {
+            Some(last_seq) => {
+                let seq = last_seq + 1;
+                let entry = encode_batch(seq, batch.updates());
+                batch.put(replication::log_key(seq), entry);
+                batch.put(replication::last_seq_key(), encode_u64(seq));
+                Ok((batch, Some(seq)))
+            },
+            None => Ok((batch, None))
+        }
+    }
+
+    /// Receive the changes made through this handle to keys starting with
+    /// `prefix`, from now on. The subscription is dropped if more than
+    /// 1024 changes are left unreceived.
+    pub fn subscribe(&self, prefix: &[u8]) -> Subscription {
+        self.subscribers.subscribe(prefix, DEFAULT_CAPACITY)
+    }
+
+    /// Like `subscribe`, dropping the subscription once `capacity` changes
+    /// are left unreceived.
+    pub fn subscribe_bounded(&self, prefix: &[u8], capacity: uint) -> Subscription {
+        self.subscribers.subscribe(prefix, capacity)
+    }
+
+    // Copy `write_batch`, adding the updates to index entries it implies
+    // and, with lazy merges, the deletion of the pending operands of every
+    // key it writes, which the written value replaces. The old value of
+    // every key written is read to remove its stale entries, so this must
+    // run under the write lock. Internal keys are copied as they are.
+    fn expand_batch(&self, hooks: &Hooks, write_batch: &WriteBatch) -> Result<WriteBatch, error> {
+        let mut batch = WriteBatch::new();
+        // Values written earlier in the batch shadow those in the database.
+        let mut pending: HashMap<~[u8], Option<~[u8]>> = HashMap::new();
+        let lazy_merge = hooks.is_lazy_merge();
+        for (key, value) in write_batch.updates().move_iter() {
+            if is_internal_key(key) {
+                match value {
+                    Some(ref value) => batch.put(key, *value),
+                    None => batch.delete(key)
+                }
+                continue;
+            }
+            if lazy_merge {
+                match self.merge_operand_keys(key) {
+                    Ok(operand_keys) => for operand_key in operand_keys.iter() {
+                        batch.delete(*operand_key);
+                    },
+                    Err(err) => return Err(err)
+                }
             }
+            if hooks.indexes.is_empty() {
+                match value {
+                    Some(ref value) => batch.put(key, *value),
+                    None => batch.delete(key)
+                }
+                continue;
+            }
+            let old = match pending.find(&key) {
+                Some(old) => old.clone(),
+                None => match self.get_raw(key, to_c_read_options([])) {
+                    Ok(old) => old,
+                    Err(err) => return Err(err)
+                }
+            };
+            for index in hooks.indexes.iter() {
+                match old {
+                    Some(ref old) => for entry in index.entry_keys(key, *old).iter() {
+                        batch.delete(*entry);
+                    },
+                    None => {}
+                }
+                match value {
+                    Some(ref value) => for entry in index.entry_keys(key, *value).iter() {
+                        batch.put(*entry, []);
+                    },
+                    None => {}
+                }
+            }
+            match value {
+                Some(ref value) => batch.put(key, *value),
+                None => batch.delete(key)
+            }
+            pending.insert(key, value);
+        }
+        Ok(batch)
+    }
+
+    fn write_raw(&self, write_batch: &WriteBatch, options: &[WriteOption]) -> Result<(), error> {
+        unsafe {
             let mut c_err: *mut c_char = mut_null();
             leveldb_write(self.db, to_c_write_options(options),
-                c_write_batch, to_mut_unsafe_ptr(&mut c_err));
+                write_batch.batch, to_mut_unsafe_ptr(&mut c_err));
             if is_not_null(c_err) {
                 return Err(from_c_str(c_err as *c_char));
             } else {
@@ -202,9 +832,101 @@ impl DB {
         }
     }
 
+    /// Take a snapshot of the current state of the database
+    pub fn snapshot<'r>(&'r self) -> Snapshot<'r> {
+        unsafe {
+            Snapshot {
+                db: self,
+                snapshot: leveldb_create_snapshot(self.db)
+            }
+        }
+    }
+
+    /// Compact the key range `[start, limit]`; `None` stands for the start
+    /// or end of the keyspace
+    pub fn compact_range(&self, start: Option<&[u8]>, limit: Option<&[u8]>) {
+        unsafe {
+            let (c_start, c_start_len) = match start {
+                Some(key) => to_c_str(key),
+                None => (ptr::null(), 0)
+            };
+            let (c_limit, c_limit_len) = match limit {
+                Some(key) => to_c_str(key),
+                None => (ptr::null(), 0)
+            };
+            leveldb_compact_range(self.db, c_start, c_start_len, c_limit, c_limit_len);
+        }
+    }
+
+    /// The value of a LevelDB property such as `leveldb.stats`, or `None`
+    /// if the property is unknown.
+    pub fn property_value(&self, name: &str) -> Option<~str> {
+        unsafe {
+            let c_value = leveldb_property_value(self.db, name.to_c_str().unwrap());
+            if is_null(c_value) {
+                return None;
+            }
+            let value = from_c_str(c_value as *c_char);
+            leveldb_free(c_value as *mut c_void);
+            Some(value)
+        }
+    }
+
+    /// Approximate file system space used by each key range `[start, limit)`
+    pub fn approximate_sizes(&self, ranges: &[(&[u8], &[u8])]) -> ~[u64] {
+        unsafe {
+            let starts: ~[*c_char] = ranges.iter().map(|&(start, _)| start.as_ptr() as *c_char).collect();
+            let start_lens: ~[size_t] = ranges.iter().map(|&(start, _)| start.len() as size_t).collect();
+            let limits: ~[*c_char] = ranges.iter().map(|&(_, limit)| limit.as_ptr() as *c_char).collect();
+            let limit_lens: ~[size_t] = ranges.iter().map(|&(_, limit)| limit.len() as size_t).collect();
+            let mut sizes = vec::from_elem(ranges.len(), 0u64);
+            leveldb_approximate_sizes(self.db, ranges.len() as c_int,
+                starts.as_ptr(), start_lens.as_ptr(),
+                limits.as_ptr(), limit_lens.as_ptr(),
+                sizes.as_mut_ptr());
+            sizes
+        }
+    }
+
+    // The size of `[start, end)`; an open end is approximated by a key
+    // of 0xff bytes.
+    fn range_size(&self, start: &[u8], end: Option<&[u8]>) -> u64 {
+        let open_end = [0xffu8, ..16];
+        let end = match end {
+            Some(end) => end,
+            None => open_end.as_slice()
+        };
+        self.approximate_sizes([(start, end)])[0]
+    }
+
+    // A key splitting `[start, end)` into halves of similar size.
+    fn split_key(&self, start: &[u8], end: Option<&[u8]>, size: u64) -> Option<~[u8]> {
+        let mut low = start.to_owned();
+        let mut high = end.map(|end| end.to_owned());
+        let mut best = None;
+        for _ in range(0, SPLIT_STEPS) {
+            let mid = match midpoint(low, high.as_ref().map(|high| high.as_slice())) {
+                Some(mid) => mid,
+                None => break
+            };
+            let left = self.range_size(start, Some(mid.as_slice()));
+            best = Some(mid.clone());
+            if left * 2 < size {
+                low = mid;
+            } else {
+                high = Some(mid);
+            }
+        }
+        best
+    }
+
     pub fn iter(&self, options: &[ReadOption]) -> DBIterator {
+        self.iter_with(to_c_read_options(options))
+    }
+
+    fn iter_with(&self, c_options: *leveldb_readoptions_t) -> DBIterator {
         unsafe {
-            let it = leveldb_create_iterator(self.db, to_c_read_options(options));
+            let it = leveldb_create_iterator(self.db, c_options);
             leveldb_iter_seek_to_first(it);
             return DBIterator{
                 iter: it
@@ -213,6 +935,38 @@ impl DB {
     }
 }
 
+/// A consistent read-only view of a database, released when dropped
+pub struct Snapshot<'r> {
+    priv db: &'r DB,
+    priv snapshot: *leveldb_snapshot_t
+}
+
+impl<'r> Snapshot<'r> {
+    pub fn get(&self, key: &[u8], options: &[ReadOption]) -> Result<~[u8], error> {
+        match self.get_opt(key, options) {
+            Ok(value) => Ok(value.unwrap_or(~[])),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn get_opt(&self, key: &[u8], options: &[ReadOption]) -> Result<Option<~[u8]>, error> {
+        self.db.get_with(key, to_c_snapshot_read_options(options, self.snapshot), true)
+    }
+
+    pub fn iter(&self, options: &[ReadOption]) -> DBIterator {
+        self.db.iter_with(to_c_snapshot_read_options(options, self.snapshot))
+    }
+}
+
+#[unsafe_destructor]
+impl<'r> Drop for Snapshot<'r> {
+    fn drop(&mut self) {
+        unsafe {
+            leveldb_release_snapshot(self.db.db, self.snapshot);
+        }
+    }
+}
+
 pub struct DBIterator {
     iter: *mut leveldb_iterator_t
 }
@@ -240,6 +994,94 @@ impl Iterator<(~[u8], ~[u8])> for DBIterator {
     }
 }
 
+/// Iterator over the entries of a key range
+pub struct RangeIterator {
+    priv iter: DBIterator,
+    priv end: Option<~[u8]>
+}
+
+impl RangeIterator {
+    pub fn get_error(&self) -> Option<error> {
+        self.iter.get_error()
+    }
+}
+
+impl Iterator<(~[u8], ~[u8])> for RangeIterator {
+    fn next(&mut self) -> Option<(~[u8], ~[u8])> {
+        match self.iter.next() {
+            Some((key, value)) => match self.end {
+                Some(ref end) if key >= *end => None,
+                _ => Some((key, value))
+            },
+            None => None
+        }
+    }
+}
+
+// Size at which bulk writes flush their batch.
+static BULK_BATCH_BYTES: uint = 4 * 1024 * 1024;
+
+// Writes updates to a database in batches of about `limit` bytes, for bulk
+// operations that need not be atomic.
+struct BatchWriter<'r> {
+    priv db: &'r DB,
+    priv batch: WriteBatch,
+    priv pending: uint,
+    priv limit: uint,
+    // Bytes of keys and values written so far.
+    priv written: u64
+}
+
+impl<'r> BatchWriter<'r> {
+    fn new(db: &'r DB, limit: uint) -> BatchWriter<'r> {
+        BatchWriter {
+            db: db,
+            batch: WriteBatch::new(),
+            pending: 0,
+            limit: limit,
+            written: 0
+        }
+    }
+
+    // `put` and `delete` return whether the batch was written.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<bool, error> {
+        self.batch.put(key, value);
+        self.pending += key.len() + value.len();
+        self.flush_if_full()
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, error> {
+        self.batch.delete(key);
+        self.pending += key.len();
+        self.flush_if_full()
+    }
+
+    fn flush_if_full(&mut self) -> Result<bool, error> {
+        if self.pending < self.limit {
+            return Ok(false);
+        }
+        match self.flush() {
+            Ok(_) => Ok(true),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), error> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        match self.db.write(&self.batch, []) {
+            Ok(_) => {
+                self.batch.clear();
+                self.written += self.pending as u64;
+                self.pending = 0;
+                Ok(())
+            },
+            Err(err) => Err(err)
+        }
+    }
+}
+
 impl DBIterator {
     pub fn prev(&mut self) -> Option<(~[u8], ~[u8])> {
         unsafe {
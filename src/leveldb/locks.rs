@@ -0,0 +1,135 @@
+//! Striped in-process key locks.
+//!
+//! Keys hash onto a fixed number of stripes and a lock is taken on the
+//! stripe, so unrelated keys occasionally contend. Each stripe has its own
+//! mutex and condition, and waiters sleep until the stripe is released or
+//! their deadline passes. Stripes are always acquired in increasing order,
+//! which rules out deadlock between callers locking several keys at once.
+//! Locks are not reentrant: a task locking a stripe it already holds waits
+//! until its timeout.
+
+use std::io::timer;
+use std::task;
+use std::vec;
+
+use extra::arc::MutexArc;
+use extra::time::precise_time_ns;
+
+use super::error;
+
+pub static DEFAULT_STRIPES: uint = 1024;
+
+/// How long operations taking key locks wait for them before giving up.
+pub static LOCK_TIMEOUT_MS: u64 = 10000;
+
+#[deriving(Clone)]
+pub struct LockTable {
+    // Whether each stripe is held.
+    priv stripes: ~[MutexArc<bool>]
+}
+
+/// Locks held on a set of stripes, released when dropped.
+pub struct LockGuard<'r> {
+    priv table: &'r LockTable,
+    priv stripes: ~[uint]
+}
+
+/// 64-bit FNV-1a.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_extend(0xcbf29ce484222325u64, bytes)
+}
+
+pub fn fnv1a_extend(hash: u64, bytes: &[u8]) -> u64 {
+    let mut hash = hash;
+    for &b in bytes.iter() {
+        hash = (hash ^ (b as u64)) * 0x100000001b3u64;
+    }
+    hash
+}
+
+impl LockTable {
+    pub fn new(stripes: uint) -> LockTable {
+        LockTable {
+            stripes: vec::from_fn(stripes, |_| MutexArc::new(false))
+        }
+    }
+
+    fn stripes_for(&self, keys: &[&[u8]]) -> ~[uint] {
+        let n = self.stripes.len() as u64;
+        let mut stripes: ~[uint] = keys.iter().map(|key| (fnv1a(*key) % n) as uint).collect();
+        stripes.sort();
+        stripes.dedup();
+        stripes
+    }
+
+    // Take `stripe`, waiting until `deadline`, in `precise_time_ns` time.
+    fn take(&self, stripe: uint, deadline: u64) -> bool {
+        let held = &self.stripes[stripe];
+        if held.access(|held| if *held { false } else { *held = true; true }) {
+            return true;
+        }
+        let now = precise_time_ns();
+        if now >= deadline {
+            return false;
+        }
+        // Waiting on a condition cannot time out, so the waiters of the
+        // stripe are woken at the deadline to give up.
+        let alarm = held.clone();
+        let wait_ms = (deadline - now) / 1000000 + 1;
+        task::spawn(proc() {
+            timer::sleep(wait_ms);
+            alarm.access_cond(|_, cond| { cond.broadcast(); });
+        });
+        held.access_cond(|held, cond| {
+            while *held {
+                if precise_time_ns() >= deadline {
+                    return false;
+                }
+                cond.wait();
+            }
+            *held = true;
+            true
+        })
+    }
+
+    fn release(&self, stripes: &[uint]) {
+        for &stripe in stripes.iter() {
+            self.stripes[stripe].access_cond(|held, cond| {
+                *held = false;
+                cond.signal();
+            });
+        }
+    }
+
+    /// Lock the stripes of `keys`, waiting at most `timeout_ms`
+    /// milliseconds. Returns `None` on timeout, holding nothing.
+    pub fn lock<'r>(&'r self, keys: &[&[u8]], timeout_ms: u64) -> Option<LockGuard<'r>> {
+        let deadline = precise_time_ns() + timeout_ms * 1000000;
+        let stripes = self.stripes_for(keys);
+        for (i, &stripe) in stripes.iter().enumerate() {
+            if !self.take(stripe, deadline) {
+                self.release(stripes.slice_to(i));
+                return None;
+            }
+        }
+        Some(LockGuard {
+            table: self,
+            stripes: stripes
+        })
+    }
+
+    /// Like `lock`, but a timeout is an error.
+    pub fn lock_within<'r>(&'r self, keys: &[&[u8]], timeout_ms: u64) -> Result<LockGuard<'r>, error> {
+        match self.lock(keys, timeout_ms) {
+            Some(guard) => Ok(guard),
+            None => Err(~"timed out waiting for key locks")
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'r> Drop for LockGuard<'r> {
+    fn drop(&mut self) {
+        self.table.release(self.stripes);
+    }
+}
@@ -0,0 +1,150 @@
+//! Reading LevelDB log files (`.log` write-ahead logs and `MANIFEST`s)
+//! without libleveldb.
+//!
+//! A log is a sequence of 32KB blocks. Records are split into fragments
+//! that never cross a block boundary, each with a 7-byte header: the
+//! masked CRC-32C of the type and data, the data length and the fragment
+//! type (FULL, or FIRST, MIDDLE... LAST). A block tail too short for a
+//! header is zero-filled. The records of a `.log` file are WriteBatch
+//! representations, decoded by `decode_batch`.
+
+use std::cmp;
+
+use super::{error, read_file};
+use super::coding::get_fixed32;
+use super::crc32c;
+
+pub use super::coding::decode_batch;
+
+pub static BLOCK_SIZE: uint = 32768;
+static HEADER_LEN: uint = 7;
+
+static ZERO_TYPE: u8 = 0;
+static FULL_TYPE: u8 = 1;
+static FIRST_TYPE: u8 = 2;
+static MIDDLE_TYPE: u8 = 3;
+static LAST_TYPE: u8 = 4;
+
+/// Damage found while reading a log
+#[deriving(Eq, Clone)]
+pub enum Damage {
+    /// `uint` bytes at the offset were dropped for the reason given
+    Corrupt(u64, uint, ~str),
+    /// The record starting at the offset is cut short by the end of the
+    /// file, as a crash in the middle of a write leaves it
+    Torn(u64)
+}
+
+/// Iterator over the records of a log and their offsets, yielding the
+/// damage it skips over in between
+pub struct LogReader {
+    priv contents: ~[u8],
+    priv pos: uint,
+    // The record being assembled from fragments, and its offset.
+    priv fragments: Option<(uint, ~[u8])>
+}
+
+impl LogReader {
+    pub fn open(path: &Path) -> Result<LogReader, error> {
+        read_file(path).map(|contents| LogReader::from_bytes(contents))
+    }
+
+    pub fn from_bytes(contents: ~[u8]) -> LogReader {
+        LogReader {
+            contents: contents,
+            pos: 0,
+            fragments: None
+        }
+    }
+
+    // Skip to the next block, reporting the bytes skipped as corrupt.
+    fn drop_block(&mut self, reason: ~str) -> Damage {
+        let start = self.pos;
+        let end = cmp::min((start / BLOCK_SIZE + 1) * BLOCK_SIZE, self.contents.len());
+        self.pos = end;
+        self.fragments = None;
+        Corrupt(start as u64, end - start, reason)
+    }
+
+    // The damage of an unfinished record at the end of the file, if any.
+    fn torn_tail(&mut self, at: uint) -> Option<Result<(u64, ~[u8]), Damage>> {
+        self.pos = self.contents.len();
+        match self.fragments.take() {
+            Some((start, _)) => Some(Err(Torn(start as u64))),
+            None if at < self.contents.len() => Some(Err(Torn(at as u64))),
+            None => None
+        }
+    }
+}
+
+impl Iterator<Result<(u64, ~[u8]), Damage>> for LogReader {
+    fn next(&mut self) -> Option<Result<(u64, ~[u8]), Damage>> {
+        loop {
+            let block_left = BLOCK_SIZE - self.pos % BLOCK_SIZE;
+            if block_left < HEADER_LEN {
+                // Zero-filled trailer.
+                self.pos += block_left;
+            }
+            if self.pos + HEADER_LEN > self.contents.len() {
+                let at = self.pos;
+                return self.torn_tail(at);
+            }
+
+            let (expected, len, kind) = {
+                let header = self.contents.slice(self.pos, self.pos + HEADER_LEN);
+                (crc32c::unmask(get_fixed32(header)),
+                 (header[4] as uint) | (header[5] as uint << 8),
+                 header[6])
+            };
+            let start = self.pos;
+            let end = start + HEADER_LEN + len;
+            if kind == ZERO_TYPE && len == 0 {
+                // Space preallocated by mmap writes.
+                self.pos += BLOCK_SIZE - self.pos % BLOCK_SIZE;
+                continue;
+            }
+            if end > self.contents.len() {
+                return self.torn_tail(start);
+            }
+            if len > BLOCK_SIZE - start % BLOCK_SIZE - HEADER_LEN {
+                return Some(Err(self.drop_block(~"bad record length")));
+            }
+            if crc32c::value(self.contents.slice(start + 6, end)) != expected {
+                return Some(Err(self.drop_block(~"checksum mismatch")));
+            }
+            let data = self.contents.slice(start + HEADER_LEN, end).to_owned();
+
+            if kind == FULL_TYPE || kind == FIRST_TYPE {
+                // Report an unfinished record before reading this one.
+                match self.fragments.take() {
+                    Some((first, fragments)) => {
+                        return Some(Err(Corrupt(first as u64, fragments.len(),
+                                                ~"record without its last fragment")));
+                    },
+                    None => {}
+                }
+                self.pos = end;
+                if kind == FULL_TYPE {
+                    return Some(Ok((start as u64, data)));
+                }
+                self.fragments = Some((start, data));
+            } else if kind == MIDDLE_TYPE || kind == LAST_TYPE {
+                self.pos = end;
+                match self.fragments {
+                    Some((_, ref mut fragments)) => fragments.push_all(data),
+                    None => return Some(Err(Corrupt(start as u64, end - start,
+                                                    ~"fragment without a first fragment")))
+                }
+                if kind == LAST_TYPE {
+                    let (first, record) = self.fragments.take_unwrap();
+                    return Some(Ok((first as u64, record)));
+                }
+            } else {
+                self.pos = end;
+                self.fragments = None;
+                return Some(Err(Corrupt(start as u64, end - start,
+                                        format!("unknown record type {}", kind))));
+            }
+        }
+    }
+}
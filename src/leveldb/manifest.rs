@@ -0,0 +1,255 @@
+//! Reading LevelDB `MANIFEST` files without libleveldb.
+//!
+//! A manifest is a log whose records are VersionEdits: the changes each
+//! compaction or memtable flush made to the set of table files per level,
+//! along with the comparator name and file and sequence counters.
+//! Replaying the edits in order gives the current version. The manifest
+//! in use is named by the `CURRENT` file of the database directory.
+
+use std::str;
+use std::vec;
+
+use super::{error, read_file};
+use super::coding::{get_length_prefixed, get_varint32, get_varint64};
+use super::log::{Damage, LogReader};
+use super::table::InternalKey;
+
+pub static NUM_LEVELS: uint = 7;
+
+static TAG_COMPARATOR: u32 = 1;
+static TAG_LOG_NUMBER: u32 = 2;
+static TAG_NEXT_FILE_NUMBER: u32 = 3;
+static TAG_LAST_SEQUENCE: u32 = 4;
+static TAG_COMPACT_POINTER: u32 = 5;
+static TAG_DELETED_FILE: u32 = 6;
+static TAG_NEW_FILE: u32 = 7;
+static TAG_PREV_LOG_NUMBER: u32 = 9;
+
+/// A table file of a version
+#[deriving(Eq, Clone)]
+pub struct FileMetaData {
+    number: u64,
+    size: u64,
+    smallest: InternalKey,
+    largest: InternalKey
+}
+
+/// One record of a manifest
+#[deriving(Eq, Clone)]
+pub struct VersionEdit {
+    comparator: Option<~str>,
+    log_number: Option<u64>,
+    prev_log_number: Option<u64>,
+    next_file_number: Option<u64>,
+    last_sequence: Option<u64>,
+    compact_pointers: ~[(uint, InternalKey)],
+    deleted_files: ~[(uint, u64)],
+    new_files: ~[(uint, FileMetaData)]
+}
+
+fn get_level(bytes: &[u8], pos: &mut uint) -> Result<uint, error> {
+    match get_varint32(bytes, pos) {
+        Some(level) if (level as uint) < NUM_LEVELS => Ok(level as uint),
+        Some(level) => Err(format!("bad level {}", level)),
+        None => Err(~"truncated level")
+    }
+}
+
+fn get_u64(bytes: &[u8], pos: &mut uint, what: &str) -> Result<u64, error> {
+    match get_varint64(bytes, pos) {
+        Some(n) => Ok(n),
+        None => Err(format!("truncated {}", what))
+    }
+}
+
+fn get_internal_key(bytes: &[u8], pos: &mut uint) -> Result<InternalKey, error> {
+    match get_length_prefixed(bytes, pos) {
+        Some(key) => InternalKey::decode(key),
+        None => Err(~"truncated internal key")
+    }
+}
+
+impl VersionEdit {
+    pub fn new() -> VersionEdit {
+        VersionEdit {
+            comparator: None,
+            log_number: None,
+            prev_log_number: None,
+            next_file_number: None,
+            last_sequence: None,
+            compact_pointers: ~[],
+            deleted_files: ~[],
+            new_files: ~[]
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<VersionEdit, error> {
+        let mut edit = VersionEdit::new();
+        let mut pos = 0u;
+        while pos < bytes.len() {
+            let tag = match get_varint32(bytes, &mut pos) {
+                Some(tag) => tag,
+                None => return Err(~"truncated tag")
+            };
+            let res = if tag == TAG_COMPARATOR {
+                match get_length_prefixed(bytes, &mut pos) {
+                    Some(name) => match str::from_utf8_opt(name) {
+                        Some(name) => {
+                            edit.comparator = Some(name.to_owned());
+                            Ok(())
+                        },
+                        None => Err(~"comparator name is not UTF-8")
+                    },
+                    None => Err(~"truncated comparator name")
+                }
+            } else if tag == TAG_LOG_NUMBER {
+                get_u64(bytes, &mut pos, "log number").map(|n| edit.log_number = Some(n))
+            } else if tag == TAG_PREV_LOG_NUMBER {
+                get_u64(bytes, &mut pos, "previous log number")
+                    .map(|n| edit.prev_log_number = Some(n))
+            } else if tag == TAG_NEXT_FILE_NUMBER {
+                get_u64(bytes, &mut pos, "next file number")
+                    .map(|n| edit.next_file_number = Some(n))
+            } else if tag == TAG_LAST_SEQUENCE {
+                get_u64(bytes, &mut pos, "last sequence").map(|n| edit.last_sequence = Some(n))
+            } else if tag == TAG_COMPACT_POINTER {
+                get_level(bytes, &mut pos).and_then(|level| {
+                    get_internal_key(bytes, &mut pos).map(|key| {
+                        edit.compact_pointers.push((level, key))
+                    })
+                })
+            } else if tag == TAG_DELETED_FILE {
+                get_level(bytes, &mut pos).and_then(|level| {
+                    get_u64(bytes, &mut pos, "file number").map(|number| {
+                        edit.deleted_files.push((level, number))
+                    })
+                })
+            } else if tag == TAG_NEW_FILE {
+                decode_new_file(bytes, &mut pos).map(|file| edit.new_files.push(file))
+            } else {
+                Err(format!("unknown tag {}", tag))
+            };
+            match res {
+                Ok(_) => {},
+                Err(err) => return Err(format!("version edit: {}", err))
+            }
+        }
+        Ok(edit)
+    }
+}
+
+fn decode_new_file(bytes: &[u8], pos: &mut uint) -> Result<(uint, FileMetaData), error> {
+    let level = match get_level(bytes, pos) {
+        Ok(level) => level,
+        Err(err) => return Err(err)
+    };
+    let number = match get_u64(bytes, pos, "file number") {
+        Ok(n) => n,
+        Err(err) => return Err(err)
+    };
+    let size = match get_u64(bytes, pos, "file size") {
+        Ok(n) => n,
+        Err(err) => return Err(err)
+    };
+    let smallest = match get_internal_key(bytes, pos) {
+        Ok(key) => key,
+        Err(err) => return Err(err)
+    };
+    get_internal_key(bytes, pos).map(|largest| {
+        (level, FileMetaData {
+            number: number,
+            size: size,
+            smallest: smallest,
+            largest: largest
+        })
+    })
+}
+
+/// The state of a database as of some manifest record
+pub struct Version {
+    comparator: Option<~str>,
+    log_number: u64,
+    prev_log_number: u64,
+    next_file_number: u64,
+    last_sequence: u64,
+    /// Where the next compaction of each level starts
+    compact_pointers: ~[Option<InternalKey>],
+    /// The table files of each level; those of level 0 by file number
+    /// and those of the other levels by smallest key
+    files: ~[~[FileMetaData]]
+}
+
+impl Version {
+    pub fn new() -> Version {
+        Version {
+            comparator: None,
+            log_number: 0,
+            prev_log_number: 0,
+            next_file_number: 0,
+            last_sequence: 0,
+            compact_pointers: vec::from_elem(NUM_LEVELS, None),
+            files: vec::from_elem(NUM_LEVELS, ~[])
+        }
+    }
+
+    pub fn apply(&mut self, edit: &VersionEdit) {
+        if edit.comparator.is_some() {
+            self.comparator = edit.comparator.clone();
+        }
+        self.log_number = edit.log_number.unwrap_or(self.log_number);
+        self.prev_log_number = edit.prev_log_number.unwrap_or(self.prev_log_number);
+        self.next_file_number = edit.next_file_number.unwrap_or(self.next_file_number);
+        self.last_sequence = edit.last_sequence.unwrap_or(self.last_sequence);
+        for &(level, ref key) in edit.compact_pointers.iter() {
+            self.compact_pointers[level] = Some(key.clone());
+        }
+        for &(level, number) in edit.deleted_files.iter() {
+            self.files[level].retain(|file| file.number != number);
+        }
+        for &(level, ref file) in edit.new_files.iter() {
+            self.files[level].push(file.clone());
+            if level == 0 {
+                self.files[level].sort_by(|a, b| a.number.cmp(&b.number));
+            } else {
+                self.files[level].sort_by(|a, b| a.smallest.user_key.cmp(&b.smallest.user_key));
+            }
+        }
+    }
+
+    /// Replay the manifest at `path`. Also returns each edit and the
+    /// damage skipped in the log; an edit that does not decode is an error.
+    pub fn replay(path: &Path) -> Result<(Version, ~[VersionEdit], ~[Damage]), error> {
+        let reader = match LogReader::open(path) {
+            Ok(reader) => reader,
+            Err(err) => return Err(err)
+        };
+        let mut version = Version::new();
+        let mut edits = ~[];
+        let mut damage = ~[];
+        for record in reader {
+            match record {
+                Ok((offset, bytes)) => match VersionEdit::decode(bytes) {
+                    Ok(edit) => {
+                        version.apply(&edit);
+                        edits.push(edit);
+                    },
+                    Err(err) => return Err(format!("record at {}: {}", offset, err))
+                },
+                Err(err) => damage.push(err)
+            }
+        }
+        Ok((version, edits, damage))
+    }
+
+    /// The path of the manifest named by the `CURRENT` file of the
+    /// database in directory `db`.
+    pub fn current_manifest(db: &Path) -> Result<Path, error> {
+        match read_file(&db.join("CURRENT")) {
+            Ok(bytes) => match str::from_utf8_opt(bytes).map(|name| name.trim()) {
+                Some(name) if name.starts_with("MANIFEST-") => Ok(db.join(name)),
+                _ => Err(~"CURRENT does not name a manifest")
+            },
+            Err(err) => Err(err)
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Read-modify-write values through merge operators.
+//!
+//! LevelDB has no merge operators, so they are emulated by the wrapper.
+//! With the `Eager` strategy `DB::merge` reads the current value, applies
+//! the operator and writes the result while holding the key's lock. With
+//! `Lazy` it only stores the operand under a key made of the original key,
+//! a reserved suffix and a sequence number; reads fold the pending operands
+//! into the value, and `collapse_merge_operands` writes the folded value
+//! back and removes them. Plain writes of a key remove its pending
+//! operands in the same batch, since the written value replaces them.
+//! Operand keys are visible to `DB::iter`, and user keys must not contain
+//! the reserved suffix.
+//!
+//! Sequence numbers start from the wall clock, and the last one is stored
+//! with each operand, so that they keep increasing across reopens even if
+//! the clock steps back.
+
+use extra::time::get_time;
+
+pub use super::coding::{encode_u64, decode_u64};
+
+use super::{DB, WriteBatch, error};
+use super::locks::LOCK_TIMEOUT_MS;
+use super::coding::{put_be32_prefixed, get_be32_prefixed};
+use super::keys::prefix_successor;
+use super::cleveldb::leveldb_iter_destroy;
+use super::options::WriteOption;
+
+static OPERAND_SUFFIX: &'static [u8] = bytes!("\x00\xffmerge\x00");
+static SEQ_KEY: &'static [u8] = bytes!("\x00merge\x00seq");
+
+pub trait MergeOperator {
+    /// Combine the current value of `key`, if any, with `operand`.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> ~[u8];
+}
+
+#[deriving(Eq, Clone)]
+pub enum MergeStrategy {
+    /// Apply the operator at merge time under a key lock.
+    Eager,
+    /// Store operands and apply the operator when reading.
+    Lazy
+}
+
+/// Treats values as big-endian u64s and adds operands to them, wrapping on
+/// overflow. A missing or malformed value counts as 0.
+pub struct U64Add;
+
+/// Keeps the largest of the big-endian u64 operands.
+pub struct U64Max;
+
+/// Appends each operand to a list; see `decode_list`.
+pub struct ListAppend;
+
+impl MergeOperator for U64Add {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> ~[u8] {
+        let current = existing.and_then(decode_u64).unwrap_or(0);
+        let delta = decode_u64(operand).unwrap_or(0);
+        encode_u64(current + delta)
+    }
+}
+
+impl MergeOperator for U64Max {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> ~[u8] {
+        let current = existing.and_then(decode_u64).unwrap_or(0);
+        let n = decode_u64(operand).unwrap_or(0);
+        encode_u64(if n > current { n } else { current })
+    }
+}
+
+impl MergeOperator for ListAppend {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> ~[u8] {
+        let mut list = match existing {
+            Some(bytes) => bytes.to_owned(),
+            None => ~[]
+        };
+        put_be32_prefixed(&mut list, operand);
+        list
+    }
+}
+
+/// Split a value built by `ListAppend` into its elements.
+pub fn decode_list(bytes: &[u8]) -> Option<~[~[u8]]> {
+    let mut items = ~[];
+    let mut pos = 0u;
+    while pos < bytes.len() {
+        match get_be32_prefixed(bytes, &mut pos) {
+            Some(item) => items.push(item.to_owned()),
+            None => return None
+        }
+    }
+    Some(items)
+}
+
+/// The prefix of the keys under which the `Lazy` strategy stores
+/// operands for `key`.
+pub fn operand_prefix(key: &[u8]) -> ~[u8] {
+    let mut prefix = key.to_owned();
+    prefix.push_all(OPERAND_SUFFIX);
+    prefix
+}
+
+/// Whether `key` holds the last operand sequence number.
+pub fn is_seq_key(key: &[u8]) -> bool {
+    key == SEQ_KEY
+}
+
+/// The base key of an operand key, if `key` is one.
+pub fn operand_base(key: &[u8]) -> Option<&[u8]> {
+    let suffix_len = OPERAND_SUFFIX.len() + 8;
+    if key.len() < suffix_len {
+        return None;
+    }
+    let base_len = key.len() - suffix_len;
+    if key.slice(base_len, base_len + OPERAND_SUFFIX.len()) == OPERAND_SUFFIX {
+        Some(key.slice_to(base_len))
+    } else {
+        None
+    }
+}
+
+impl DB {
+    /// Set the merge operator used by `merge` and, with the `Lazy`
+    /// strategy, by reads, on every handle on this database.
+    pub fn set_merge_operator(&mut self, operator: ~MergeOperator:Send,
+                              strategy: MergeStrategy) -> Result<(), error> {
+        let stored_seq = match self.get_opt(SEQ_KEY, []) {
+            Ok(Some(bytes)) => decode_u64(bytes).unwrap_or(0),
+            Ok(None) => 0,
+            Err(err) => return Err(err)
+        };
+        self.merge_seq.access(|last| if stored_seq > *last { *last = stored_seq });
+        let mut merge = Some((operator, strategy));
+        self.with_hooks(|hooks| hooks.merge = merge.take());
+        unsafe {
+            self.merge_strategy.unsafe_access(|cached| *cached = Some(strategy));
+        }
+        Ok(())
+    }
+
+    /// Merge `operand` into the value of `key`.
+    pub fn merge(&self, key: &[u8], operand: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        match self.merge_strategy() {
+            Some(Eager) => {
+                let _guard = match self.key_locks.lock_within([key], LOCK_TIMEOUT_MS) {
+                    Ok(guard) => guard,
+                    Err(err) => return Err(err)
+                };
+                match self.get_opt(key, []) {
+                    Ok(existing) => {
+                        let existing = existing.as_ref().map(|v| v.as_slice());
+                        self.put(key, self.apply_merge_operator(key, existing, operand), options)
+                    },
+                    Err(err) => Err(err)
+                }
+            },
+            // Numbered under the write lock, so that the stored sequence
+            // number only increases.
+            Some(Lazy) => self.write_lock.lock(|| {
+                let seq = self.next_merge_seq();
+                let mut operand_key = operand_prefix(key);
+                operand_key.push_all(encode_u64(seq));
+                let mut batch = WriteBatch::new();
+                batch.put(operand_key, operand);
+                batch.put(SEQ_KEY, encode_u64(seq));
+                self.write_unlocked(&batch, options)
+            }),
+            None => Err(~"no merge operator set")
+        }
+    }
+
+    // Operand sequence numbers start from the wall clock in nanoseconds,
+    // or from the last one stored if the clock is behind it.
+    fn next_merge_seq(&self) -> u64 {
+        self.merge_seq.access(|last| {
+            let now = get_time();
+            let now = (now.sec as u64) * 1000000000 + (now.nsec as u64);
+            *last = if now > *last { now } else { *last + 1 };
+            *last
+        })
+    }
+
+    /// Replace the pending operands of `key` by the folded value.
+    ///
+    /// The fold and the write happen under the write lock, so operands
+    /// merged meanwhile are neither lost nor applied twice. Writing the
+    /// value removes the operands it folded.
+    pub fn collapse_merge_operands(&self, key: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        self.write_lock.lock(|| {
+            match self.merge_operand_keys(key) {
+                Ok(operand_keys) if operand_keys.is_empty() => Ok(()),
+                Ok(_) => match self.get_opt(key, []) {
+                    Ok(Some(value)) => {
+                        let mut batch = WriteBatch::new();
+                        batch.put(key, value);
+                        self.write_unlocked(&batch, options)
+                    },
+                    Ok(None) => Ok(()),
+                    Err(err) => Err(err)
+                },
+                Err(err) => Err(err)
+            }
+        })
+    }
+
+    /// Collapse the pending operands of every key. Returns the number of
+    /// keys collapsed.
+    pub fn collapse_all_merge_operands(&self, options: &[WriteOption]) -> Result<uint, error> {
+        let mut count = 0u;
+        let mut it = self.iter([]);
+        let mut res = Ok(());
+        loop {
+            let base = match it.next() {
+                Some((key, _)) => match operand_base(key) {
+                    Some(base) => base.to_owned(),
+                    None => continue
+                },
+                None => break
+            };
+            match self.collapse_merge_operands(base, options) {
+                Ok(_) => count += 1,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            }
+            // Skip the remaining operands of this key.
+            match prefix_successor(operand_prefix(base)) {
+                Some(next) => it.seek(next),
+                None => break
+            }
+        }
+        if res.is_ok() {
+            res = match it.get_error() {
+                Some(err) => Err(err),
+                None => Ok(())
+            };
+        }
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        match res {
+            Ok(_) => Ok(count),
+            Err(err) => Err(err)
+        }
+    }
+}
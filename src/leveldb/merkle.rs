@@ -0,0 +1,261 @@
+//! Range digests and Merkle trees for comparing replicas.
+//!
+//! A digest hashes the length-prefixed keys and values of a range in
+//! order, with 64-bit FNV-1a: it detects divergence, not tampering. A
+//! tree splits a range until the pieces are at most `leaf_bytes` by
+//! `approximate_sizes`, which only counts data already in table files.
+//! Since split points depend on one database's sizes, a replica is
+//! compared by hashing the same layout with `merkle_tree_like`. Diffing
+//! the two trees gives the leaf ranges whose digests differ, and
+//! `repair_ranges` copies those ranges over from the source.
+
+use super::{DB, DBIterator, RangeIterator, BatchWriter, BULK_BATCH_BYTES, error};
+use super::cleveldb::leveldb_iter_destroy;
+use super::diff::{diff, DiffIterator, OnlyInA, OnlyInB, Changed};
+use super::coding::put_be32;
+use super::locks::fnv1a_extend;
+
+static FNV_OFFSET: u64 = 0xcbf29ce484222325;
+static MAX_DEPTH: uint = 32;
+
+#[deriving(Eq, Clone)]
+pub struct RangeDigest {
+    hash: u64,
+    entries: u64
+}
+
+impl RangeDigest {
+    fn new() -> RangeDigest {
+        RangeDigest {
+            hash: FNV_OFFSET,
+            entries: 0
+        }
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        for part in [key, value].iter() {
+            let mut len = ~[];
+            put_be32(&mut len, part.len() as u32);
+            self.hash = fnv1a_extend(self.hash, len);
+            self.hash = fnv1a_extend(self.hash, *part);
+        }
+        self.entries += 1;
+    }
+
+    fn combine(children: &[MerkleNode]) -> RangeDigest {
+        let mut digest = RangeDigest::new();
+        for child in children.iter() {
+            let hash = child.digest.hash;
+            let bytes: ~[u8] = range(0u64, 8).map(|i| (hash >> (56 - 8 * i)) as u8).collect();
+            digest.hash = fnv1a_extend(digest.hash, bytes);
+            digest.entries += child.digest.entries;
+        }
+        digest
+    }
+}
+
+/// A node covering `[start, end)`, or `[start, ...)` if `end` is `None`
+#[deriving(Eq, Clone)]
+pub struct MerkleNode {
+    start: ~[u8],
+    end: Option<~[u8]>,
+    digest: RangeDigest,
+    children: ~[MerkleNode]
+}
+
+pub struct MerkleTree {
+    root: MerkleNode
+}
+
+impl MerkleTree {
+    /// The leaf ranges of this tree whose digests differ from those of
+    /// `other`, which must have the same layout.
+    pub fn diff(&self, other: &MerkleTree) -> Result<~[(~[u8], Option<~[u8]>)], error> {
+        let mut ranges = ~[];
+        match diff_nodes(&self.root, &other.root, &mut ranges) {
+            Ok(_) => Ok(ranges),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+fn diff_nodes(a: &MerkleNode, b: &MerkleNode,
+              ranges: &mut ~[(~[u8], Option<~[u8]>)]) -> Result<(), error> {
+    if a.start != b.start || a.end != b.end || a.children.len() != b.children.len() {
+        return Err(~"trees have different layouts");
+    }
+    if a.digest == b.digest {
+        return Ok(());
+    }
+    if a.children.is_empty() {
+        ranges.push((a.start.clone(), a.end.clone()));
+        return Ok(());
+    }
+    for (child_a, child_b) in a.children.iter().zip(b.children.iter()) {
+        match diff_nodes(child_a, child_b, ranges) {
+            Ok(_) => {},
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+fn digest_to(it: &mut DBIterator, end: Option<&[u8]>) -> RangeDigest {
+    let mut digest = RangeDigest::new();
+    while it.is_valid() {
+        let key = it.key();
+        match end {
+            Some(end) if key.as_slice() >= end => break,
+            _ => {}
+        }
+        digest.add(key, it.value());
+        it.next();
+    }
+    digest
+}
+
+impl DB {
+    /// A digest of the entries in `[start, end)`, or from `start` on.
+    pub fn range_digest(&self, start: &[u8], end: Option<&[u8]>) -> Result<RangeDigest, error> {
+        let snapshot = self.snapshot();
+        let mut it = snapshot.iter([]);
+        it.seek(start);
+        let digest = digest_to(&mut it, end);
+        let err = it.get_error();
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        match err {
+            Some(err) => Err(err),
+            None => Ok(digest)
+        }
+    }
+
+    /// Split `[start, end)` into leaves of at most about `leaf_bytes` and
+    /// hash them under one snapshot.
+    pub fn merkle_tree(&self, start: &[u8], end: Option<&[u8]>,
+                       leaf_bytes: u64) -> Result<MerkleTree, error> {
+        let layout = self.layout(start, end, leaf_bytes, 0);
+        self.merkle_tree_like(&MerkleTree { root: layout })
+    }
+
+    fn layout(&self, start: &[u8], end: Option<&[u8]>, leaf_bytes: u64,
+              depth: uint) -> MerkleNode {
+        let mut node = MerkleNode {
+            start: start.to_owned(),
+            end: end.map(|end| end.to_owned()),
+            digest: RangeDigest::new(),
+            children: ~[]
+        };
+        let size = self.range_size(start, end);
+        if size <= leaf_bytes || depth == MAX_DEPTH {
+            return node;
+        }
+        match self.split_key(start, end, size) {
+            Some(mid) => {
+                node.children.push(self.layout(start, Some(mid.as_slice()), leaf_bytes, depth + 1));
+                node.children.push(self.layout(mid, end, leaf_bytes, depth + 1));
+            },
+            None => {}
+        }
+        node
+    }
+
+    /// Hash this database with the layout of `tree`, typically built on
+    /// another replica.
+    pub fn merkle_tree_like(&self, tree: &MerkleTree) -> Result<MerkleTree, error> {
+        let snapshot = self.snapshot();
+        let mut it = snapshot.iter([]);
+        it.seek(tree.root.start);
+        let root = rehash(&mut it, &tree.root);
+        let err = it.get_error();
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        match err {
+            Some(err) => Err(err),
+            None => Ok(MerkleTree { root: root })
+        }
+    }
+
+    /// Make `[start, end)` of this database match `source` for each of
+    /// `ranges`, in batched writes. Returns the number of keys written.
+    pub fn repair_ranges(&self, source: &DB,
+                         ranges: &[(~[u8], Option<~[u8]>)]) -> Result<u64, error> {
+        let source_snapshot = source.snapshot();
+        let mut written = 0u64;
+        let mut writer = BatchWriter::new(self, BULK_BATCH_BYTES);
+        for &(ref start, ref end) in ranges.iter() {
+            let mut from = source_snapshot.iter([]);
+            from.seek(*start);
+            let snapshot = self.snapshot();
+            let mut to = snapshot.iter([]);
+            to.seek(*start);
+            let from = RangeIterator { iter: from, end: end.clone() };
+            let to = RangeIterator { iter: to, end: end.clone() };
+            let mut diffs = diff(from, to);
+            let res = repair_diffs(&mut diffs, &mut writer);
+            let (from, to) = diffs.streams();
+            unsafe {
+                leveldb_iter_destroy(from.iter.iter);
+                leveldb_iter_destroy(to.iter.iter);
+            }
+            match res {
+                Ok(n) => written += n,
+                Err(err) => return Err(err)
+            }
+        }
+        match writer.flush() {
+            Ok(_) => Ok(written),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+// Write the differences in `diffs` with `writer`. An iterator that met an
+// error has skipped entries, whose keys would be deleted or rewritten
+// wrongly, so both are checked before each write.
+fn repair_diffs(diffs: &mut DiffIterator<RangeIterator, RangeIterator>,
+                writer: &mut BatchWriter) -> Result<u64, error> {
+    let mut written = 0u64;
+    loop {
+        let d = match diffs.next() {
+            Some(d) => d,
+            None => break
+        };
+        let (from, to) = diffs.streams();
+        match from.get_error().or(to.get_error()) {
+            Some(err) => return Err(err),
+            None => {}
+        }
+        let res = match d {
+            OnlyInA(key, value) | Changed(key, value, _) => writer.put(key, value),
+            OnlyInB(key, _) => writer.delete(key)
+        };
+        match res {
+            Ok(_) => written += 1,
+            Err(err) => return Err(err)
+        }
+    }
+    let (from, to) = diffs.streams();
+    match from.get_error().or(to.get_error()) {
+        Some(err) => Err(err),
+        None => Ok(written)
+    }
+}
+
+fn rehash(it: &mut DBIterator, node: &MerkleNode) -> MerkleNode {
+    let end = node.end.as_ref().map(|end| end.as_slice());
+    let (digest, children) = if node.children.is_empty() {
+        (digest_to(it, end), ~[])
+    } else {
+        let children: ~[MerkleNode] = node.children.iter().map(|child| rehash(it, child)).collect();
+        (RangeDigest::combine(children), children)
+    };
+    MerkleNode {
+        start: node.start.clone(),
+        end: node.end.clone(),
+        digest: digest,
+        children: children
+    }
+}
@@ -0,0 +1,55 @@
+//! Consistent multi-key reads.
+
+use std::vec;
+
+use super::{DB, Snapshot, error, to_c_snapshot_read_options};
+use super::cleveldb::{leveldb_readoptions_t, leveldb_readoptions_destroy};
+use super::options::ReadOption;
+
+impl DB {
+    /// Read every key in `keys` from a single snapshot, so the values are
+    /// mutually consistent. Results are in the order of `keys`; missing
+    /// keys give `None`.
+    pub fn multi_get(&self, keys: &[&[u8]], options: &[ReadOption]) -> Result<~[Option<~[u8]>], error> {
+        self.snapshot().multi_get(keys, options)
+    }
+}
+
+impl<'r> Snapshot<'r> {
+    /// Read every key in `keys` from this snapshot. Results are in the
+    /// order of `keys`; missing keys give `None`.
+    ///
+    /// Keys are looked up in sorted order, which keeps neighbouring reads
+    /// on the same blocks, and one set of read options is shared by all
+    /// lookups.
+    pub fn multi_get(&self, keys: &[&[u8]], options: &[ReadOption]) -> Result<~[Option<~[u8]>], error> {
+        let c_options = to_c_snapshot_read_options(options, snapshot.snapshot);
+        let mut order: ~[uint] = range(0, keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+        let mut values: ~[Option<~[u8]>] = vec::from_fn(keys.len(), |_| None);
+        let mut res = Ok(());
+        let mut prev: Option<uint> = None;
+        for &i in order.iter() {
+            match prev {
+                Some(p) if keys[p] == keys[i] => {
+                    values[i] = values[p].clone();
+                },
+                _ => match self.db.get_with(keys[i], c_options, true) {
+                    Ok(value) => values[i] = value,
+                    Err(err) => {
+                        res = Err(err);
+                        break;
+                    }
+                }
+            }
+            prev = Some(i);
+        }
+        unsafe {
+            leveldb_readoptions_destroy(c_options as *mut leveldb_readoptions_t);
+        }
+        match res {
+            Ok(_) => Ok(values),
+            Err(err) => Err(err)
+        }
+    }
+}
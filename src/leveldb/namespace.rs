@@ -0,0 +1,217 @@
+//! Named keyspaces sharing one database.
+//!
+//! Each namespace is given a numeric id the first time it is opened and
+//! its keys are stored under a short prefix derived from that id. The
+//! name-to-id mapping is kept in a single registry key. Keys written
+//! through a `Namespace` are visible to `DB::iter` with their prefix.
+
+use std::str;
+
+use super::{DB, DBIterator, WriteBatch, error};
+use super::coding::{put_be32, put_be32_prefixed, get_be32_prefixed};
+use super::keys::prefix_successor;
+use super::options::{ReadOption, WriteOption, SYNC};
+
+static REGISTRY_KEY: &'static [u8] = bytes!("\x00namespaces");
+static PREFIX_TAG: &'static [u8] = bytes!("\x00ns\x00");
+
+/// A handle on a namespace of a `DB`
+pub struct Namespace<'r> {
+    priv db: &'r DB,
+    priv name: ~str,
+    priv prefix: ~[u8]
+}
+
+/// Whether `key` is the namespace registry.
+pub fn is_registry_key(key: &[u8]) -> bool {
+    key == REGISTRY_KEY
+}
+
+fn prefix_for(id: u32) -> ~[u8] {
+    let mut prefix = PREFIX_TAG.to_owned();
+    put_be32(&mut prefix, id);
+    prefix
+}
+
+// The registry is a sequence of (u32 length, name) records; a name's id is
+// its position in the sequence, starting at 1.
+fn decode_registry(bytes: &[u8]) -> Result<~[~str], error> {
+    let mut names = ~[];
+    let mut pos = 0u;
+    while pos < bytes.len() {
+        let name = match get_be32_prefixed(bytes, &mut pos) {
+            Some(name) => name,
+            None => return Err(~"corrupt namespace registry")
+        };
+        match str::from_utf8_opt(name) {
+            Some(name) => names.push(name.to_owned()),
+            None => return Err(~"corrupt namespace registry")
+        }
+    }
+    Ok(names)
+}
+
+fn encode_registry(names: &[~str]) -> ~[u8] {
+    let mut bytes = ~[];
+    for name in names.iter() {
+        put_be32_prefixed(&mut bytes, name.as_bytes());
+    }
+    bytes
+}
+
+impl DB {
+    /// Names of all registered namespaces
+    pub fn namespaces(&self) -> Result<~[~str], error> {
+        match self.get_opt(REGISTRY_KEY, []) {
+            Ok(Some(bytes)) => decode_registry(bytes),
+            Ok(None) => Ok(~[]),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Open the namespace called `name`, registering it if it is new.
+    pub fn namespace<'r>(&'r self, name: &str) -> Result<Namespace<'r>, error> {
+        let id = match self.namespaces() {
+            Ok(names) => names.iter().position(|n| n.as_slice() == name).map(|i| i + 1),
+            Err(err) => return Err(err)
+        };
+        let id = match id {
+            Some(id) => id,
+            // Registration reads and rewrites the registry under the write
+            // lock, so concurrent first opens agree on the ids.
+            None => match self.write_lock.lock(|| self.register_namespace(name)) {
+                Ok(id) => id,
+                Err(err) => return Err(err)
+            }
+        };
+        Ok(Namespace {
+            db: self,
+            name: name.to_owned(),
+            prefix: prefix_for(id as u32)
+        })
+    }
+
+    // The id of `name`, registering it if another writer has not. Must be
+    // called under the write lock.
+    fn register_namespace(&self, name: &str) -> Result<uint, error> {
+        let mut names = match self.namespaces() {
+            Ok(names) => names,
+            Err(err) => return Err(err)
+        };
+        match names.iter().position(|n| n.as_slice() == name) {
+            Some(i) => return Ok(i + 1),
+            None => {}
+        }
+        names.push(name.to_owned());
+        match self.put_unlocked(REGISTRY_KEY, encode_registry(names), [SYNC]) {
+            Ok(_) => Ok(names.len()),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+impl<'r> Namespace<'r> {
+    pub fn name<'a>(&'a self) -> &'a str {
+        self.name.as_slice()
+    }
+
+    fn key(&self, key: &[u8]) -> ~[u8] {
+        let mut prefixed = self.prefix.clone();
+        prefixed.push_all(key);
+        prefixed
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        self.db.put(self.key(key), value, options)
+    }
+
+    pub fn get(&self, key: &[u8], options: &[ReadOption]) -> Result<~[u8], error> {
+        self.db.get(self.key(key), options)
+    }
+
+    pub fn get_opt(&self, key: &[u8], options: &[ReadOption]) -> Result<Option<~[u8]>, error> {
+        self.db.get_opt(self.key(key), options)
+    }
+
+    pub fn delete(&self, key: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        self.db.delete(self.key(key), options)
+    }
+
+    /// Add a put of `key` in this namespace to `batch`. A batch may hold
+    /// updates to several namespaces and is applied atomically by
+    /// `DB::write`.
+    pub fn batch_put(&self, batch: &mut WriteBatch, key: &[u8], value: &[u8]) {
+        batch.put(self.key(key), value);
+    }
+
+    /// Add a delete of `key` in this namespace to `batch`.
+    pub fn batch_delete(&self, batch: &mut WriteBatch, key: &[u8]) {
+        batch.delete(self.key(key));
+    }
+
+    /// Iterate over the keys of this namespace, with the prefix removed.
+    pub fn iter(&self, options: &[ReadOption]) -> NamespaceIterator {
+        let mut it = self.db.iter(options);
+        it.seek(self.prefix);
+        NamespaceIterator {
+            iter: it,
+            prefix: self.prefix.clone()
+        }
+    }
+
+    /// Approximate space used by each key range `[start, limit)` of this
+    /// namespace
+    pub fn approximate_sizes(&self, ranges: &[(&[u8], &[u8])]) -> ~[u64] {
+        let keys: ~[(~[u8], ~[u8])] = ranges.iter().map(|&(start, limit)| {
+            (self.key(start), self.key(limit))
+        }).collect();
+        let c_ranges: ~[(&[u8], &[u8])] = keys.iter().map(|&(ref start, ref limit)| {
+            (start.as_slice(), limit.as_slice())
+        }).collect();
+        self.db.approximate_sizes(c_ranges)
+    }
+
+    /// Approximate space used by the whole namespace
+    pub fn approximate_size(&self) -> u64 {
+        let limit = prefix_successor(self.prefix).unwrap();
+        self.db.approximate_sizes([(self.prefix.as_slice(), limit.as_slice())])[0]
+    }
+}
+
+/// Iterator over the `(key, value)` pairs of a namespace
+pub struct NamespaceIterator {
+    priv iter: DBIterator,
+    priv prefix: ~[u8]
+}
+
+impl Iterator<(~[u8], ~[u8])> for NamespaceIterator {
+    fn next(&mut self) -> Option<(~[u8], ~[u8])> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                if key.starts_with(self.prefix) {
+                    Some((key.slice_from(self.prefix.len()).to_owned(), value))
+                } else {
+                    None
+                }
+            },
+            None => None
+        }
+    }
+}
+
+impl NamespaceIterator {
+    pub fn get_error(&self) -> Option<error> {
+        self.iter.get_error()
+    }
+
+    /// Position the iterator at the first key `>= key` in the namespace.
+    pub fn seek(&mut self, key: &[u8]) {
+        let mut prefixed = self.prefix.clone();
+        prefixed.push_all(key);
+        self.iter.seek(prefixed);
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.iter.seek(self.prefix);
+    }
+}
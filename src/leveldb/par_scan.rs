@@ -0,0 +1,115 @@
+//! Parallel scans.
+//!
+//! `par_scan` splits a range into partitions of similar size, by
+//! bisecting keys with `approximate_sizes`, and scans each in its own
+//! task through a snapshot shared by all of them. Sizes only count data
+//! already in table files, so a database whose writes are all still in
+//! its memtable is scanned as one partition.
+
+use std::task;
+
+use super::{DB, RangeIterator, error, to_c_snapshot_read_options};
+use super::cleveldb::{leveldb_snapshot_t, leveldb_iter_destroy};
+
+/// Scans one partition. A bare function rather than a closure, since it
+/// is sent to the scanning tasks.
+pub type Scanner<T> = fn(entries: &mut RangeIterator) -> T;
+
+impl DB {
+    /// Split `[start, end)`, or everything from `start` on, into at most
+    /// `n` partitions of similar size, in key order.
+    pub fn partitions(&self, start: &[u8], end: Option<&[u8]>,
+                      n: uint) -> ~[(~[u8], Option<~[u8]>)] {
+        // Each partition with its size, and whether it may be split.
+        let mut parts = ~[(start.to_owned(), end.map(|end| end.to_owned()),
+                           self.range_size(start, end), true)];
+        while parts.len() < n {
+            let mut largest = None;
+            for (i, &(_, _, size, splittable)) in parts.iter().enumerate() {
+                let is_larger = match largest {
+                    Some(j) => {
+                        let (_, _, largest_size, _) = parts[j];
+                        size > largest_size
+                    },
+                    None => true
+                };
+                if splittable && size > 0 && is_larger {
+                    largest = Some(i);
+                }
+            }
+            let i = match largest {
+                Some(i) => i,
+                None => break
+            };
+            let (start, end, size, _) = parts[i].clone();
+            let split = self.split_key(start, end.as_ref().map(|end| end.as_slice()), size);
+            match split {
+                Some(mid) => {
+                    let left = self.range_size(start, Some(mid.as_slice()));
+                    let right = self.range_size(mid, end.as_ref().map(|end| end.as_slice()));
+                    parts[i] = (start, Some(mid.clone()), left, true);
+                    parts.insert(i + 1, (mid, end, right, true));
+                },
+                None => parts[i] = (start, end, size, false)
+            }
+        }
+        parts.move_iter().map(|(start, end, _, _)| (start, end)).collect()
+    }
+
+    /// Run `scan` over each of up to `n` partitions of `[start, end)` in
+    /// parallel and fold the results, in key order, with `combine`.
+    pub fn par_scan<T: Send>(&self, start: &[u8], end: Option<&[u8]>, n: uint,
+                             scan: Scanner<T>,
+                             combine: |T, T| -> T) -> Result<T, error> {
+        let snapshot = self.snapshot();
+        let mut ports = ~[];
+        for (part_start, part_end) in self.partitions(start, end, n).move_iter() {
+            let handle = self.shared_handle();
+            // Raw pointers cannot be sent; the snapshot outlives the tasks
+            // since every result is received before it is released.
+            let c_snapshot = snapshot.snapshot as uint;
+            let (port, chan) = Chan::new();
+            task::spawn(proc() {
+                let db = handle.open();
+                let c_options = to_c_snapshot_read_options([],
+                    c_snapshot as *leveldb_snapshot_t);
+                let mut iter = db.iter_with(c_options);
+                iter.seek(part_start);
+                let mut entries = RangeIterator {
+                    iter: iter,
+                    end: part_end
+                };
+                let result = scan(&mut entries);
+                let err = entries.get_error();
+                unsafe {
+                    leveldb_iter_destroy(entries.iter.iter);
+                }
+                chan.send(match err {
+                    Some(err) => Err(err),
+                    None => Ok(result)
+                });
+            });
+            ports.push(port);
+        }
+
+        let mut combined = None;
+        let mut failure = None;
+        for port in ports.iter() {
+            match port.recv_opt() {
+                Some(Ok(result)) => {
+                    combined = match combined.take() {
+                        Some(acc) => Some(combine(acc, result)),
+                        None => Some(result)
+                    };
+                },
+                Some(Err(err)) => failure = Some(err),
+                None => failure = Some(~"scan task failed")
+            }
+        }
+        match failure {
+            Some(err) => Err(err),
+            // There is always at least one partition.
+            None => Ok(combined.unwrap())
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! A change log for replicating a database to followers.
+//!
+//! Once enabled, every batch written to the database is also recorded
+//! under a reserved key range, in the same atomic write, keyed by a
+//! sequence number that increases by one per batch. Entries hold the batch
+//! in LevelDB's WriteBatch representation. A `Follower` reads the entries
+//! it has not applied yet through a handle on the primary and applies them
+//! to its own database, together with the sequence number it reached, so
+//! it resumes where it stopped after a restart. Followers acknowledge the
+//! entries they applied, and `truncate_change_log` drops the entries every
+//! follower has acknowledged.
+//!
+//! The log lives in the primary database itself, and LevelDB's LOCK file
+//! lets only one process open a database. A follower therefore runs in the
+//! process holding the primary open; replicas in other processes need that
+//! process to ship the entries returned by `read_change_log` to them.
+
+use super::{DB, Snapshot, WriteBatch, WriteBatchVisitor, error};
+use super::cleveldb::leveldb_iter_destroy;
+use super::coding::{decode_batch, decode_u64, encode_u64};
+
+static LOG_PREFIX: &'static [u8] = bytes!("\x00log\x00");
+static LAST_SEQ_KEY: &'static [u8] = bytes!("\x00log\x01last");
+static ACK_PREFIX: &'static [u8] = bytes!("\x00log\x02");
+static ACK_END: &'static [u8] = bytes!("\x00log\x03");
+static APPLIED_KEY: &'static [u8] = bytes!("\x00log\x04applied");
+
+/// The key of the change log entry with sequence number `seq`.
+pub fn log_key(seq: u64) -> ~[u8] {
+    let mut key = LOG_PREFIX.to_owned();
+    key.push_all(encode_u64(seq));
+    key
+}
+
+/// The key holding the sequence number of the last change log entry.
+pub fn last_seq_key() -> &'static [u8] {
+    LAST_SEQ_KEY
+}
+
+/// Whether `key` is one of the change log's entries, acknowledgements or
+/// sequence numbers.
+pub fn is_log_key(key: &[u8]) -> bool {
+    key.starts_with(LOG_PREFIX) || key == LAST_SEQ_KEY || key == APPLIED_KEY
+        || (key >= ACK_PREFIX && key < ACK_END)
+}
+
+fn ack_key(follower: &str) -> ~[u8] {
+    let mut key = ACK_PREFIX.to_owned();
+    key.push_all(follower.as_bytes());
+    key
+}
+
+fn read_seq(db: &DB, key: &[u8]) -> Result<u64, error> {
+    match db.get_opt(key, []) {
+        Ok(Some(bytes)) => match decode_u64(bytes) {
+            Some(seq) => Ok(seq),
+            None => Err(~"corrupt change log sequence number")
+        },
+        Ok(None) => Ok(0),
+        Err(err) => Err(err)
+    }
+}
+
+impl WriteBatchVisitor for WriteBatch {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        WriteBatch::put(self, key, value);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        WriteBatch::delete(self, key);
+    }
+}
+
+impl DB {
+    /// Record every batch written through any handle on this database
+    /// from now on.
+    pub fn enable_change_log(&mut self) -> Result<(), error> {
+        self.write_lock.lock(|| {
+            match read_seq(self, LAST_SEQ_KEY) {
+                Ok(seq) => {
+                    self.with_hooks(|hooks| hooks.change_log = Some(seq));
+                    Ok(())
+                },
+                Err(err) => Err(err)
+            }
+        })
+    }
+
+    /// The sequence number of the last recorded batch, if the change log
+    /// is enabled.
+    pub fn change_log_seq(&self) -> Option<u64> {
+        self.with_hooks(|hooks| hooks.change_log)
+    }
+
+    /// Up to `limit` change log entries with sequence numbers above
+    /// `after`, in order.
+    pub fn read_change_log(&self, after: u64, limit: uint) -> Result<~[(u64, ~[u8])], error> {
+        let mut entries = ~[];
+        let mut it = self.iter([]);
+        it.seek(log_key(after + 1));
+        while entries.len() < limit && it.is_valid() {
+            let key = it.key();
+            if !key.starts_with(LOG_PREFIX) {
+                break;
+            }
+            let seq = decode_u64(key.slice_from(LOG_PREFIX.len())).unwrap();
+            entries.push((seq, it.value()));
+            it.next();
+        }
+        let err = it.get_error();
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        match err {
+            Some(err) => Err(err),
+            None => Ok(entries)
+        }
+    }
+
+    /// Record that `follower` has applied every entry up to `seq`.
+    pub fn ack_change_log(&self, follower: &str, seq: u64) -> Result<(), error> {
+        self.write_lock.lock(|| self.ack_change_log_unlocked(follower, seq))
+    }
+
+    fn ack_change_log_unlocked(&self, follower: &str, seq: u64) -> Result<(), error> {
+        let mut batch = WriteBatch::new();
+        batch.put(ack_key(follower), encode_u64(seq));
+        self.write_raw(&batch, [])
+    }
+
+    /// Take a snapshot and register `follower` as having applied every
+    /// entry up to the snapshot's sequence number, which is returned. No
+    /// batch is logged and the log is not truncated in between, so a copy
+    /// of the snapshot can then be brought up to date by a `Follower`.
+    pub fn snapshot_for_follower<'r>(&'r self, follower: &str) -> Result<(Snapshot<'r>, u64), error> {
+        self.write_lock.lock(|| {
+            let snapshot = self.snapshot();
+            match self.change_log_seq() {
+                Some(seq) => match self.ack_change_log_unlocked(follower, seq) {
+                    Ok(_) => Ok((snapshot, seq)),
+                    Err(err) => Err(err)
+                },
+                None => Err(~"the change log is not enabled")
+            }
+        })
+    }
+
+    /// Forget `follower`, so that it no longer holds back truncation.
+    pub fn remove_follower(&self, follower: &str) -> Result<(), error> {
+        let mut batch = WriteBatch::new();
+        batch.delete(ack_key(follower));
+        self.write_lock.lock(|| self.write_raw(&batch, []))
+    }
+
+    /// Delete the change log entries acknowledged by every follower and
+    /// return how many were deleted. Nothing is deleted while no follower
+    /// has acknowledged anything.
+    pub fn truncate_change_log(&self) -> Result<uint, error> {
+        // The acknowledgements are read under the write lock, so that a
+        // follower registered meanwhile holds back the truncation.
+        self.write_lock.lock(|| {
+            let mut upto = None;
+            let mut it = self.iter([]);
+            it.seek(ACK_PREFIX);
+            while it.is_valid() {
+                if it.key().as_slice() >= ACK_END {
+                    break;
+                }
+                let seq = decode_u64(it.value()).unwrap_or(0);
+                upto = match upto {
+                    Some(min) if min <= seq => Some(min),
+                    _ => Some(seq)
+                };
+                it.next();
+            }
+            let err = it.get_error();
+            unsafe {
+                leveldb_iter_destroy(it.iter);
+            }
+            match err {
+                Some(err) => return Err(err),
+                None => {}
+            }
+            match upto {
+                Some(seq) => self.truncate_change_log_unlocked(seq),
+                None => Ok(0)
+            }
+        })
+    }
+
+    /// Delete the change log entries up to and including `seq`.
+    pub fn truncate_change_log_to(&self, seq: u64) -> Result<uint, error> {
+        self.write_lock.lock(|| self.truncate_change_log_unlocked(seq))
+    }
+
+    fn truncate_change_log_unlocked(&self, seq: u64) -> Result<uint, error> {
+        let end = log_key(seq + 1);
+        let mut batch = WriteBatch::new();
+        let mut count = 0u;
+        let mut it = self.iter([]);
+        it.seek(LOG_PREFIX);
+        while it.is_valid() {
+            let key = it.key();
+            if key >= end {
+                break;
+            }
+            batch.delete(key);
+            count += 1;
+            it.next();
+        }
+        let err = it.get_error();
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+        }
+        match err {
+            Some(err) => return Err(err),
+            None => {}
+        }
+        match self.write_raw(&batch, []) {
+            Ok(_) => Ok(count),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+/// Applies the change log of a primary database to a replica, both open in
+/// this process
+pub struct Follower<'r> {
+    priv primary: &'r DB,
+    priv replica: &'r DB,
+    priv name: ~str
+}
+
+impl<'r> Follower<'r> {
+    /// A follower called `name`; the name identifies its acknowledgements
+    /// on the primary.
+    pub fn new(primary: &'r DB, replica: &'r DB, name: &str) -> Follower<'r> {
+        Follower {
+            primary: primary,
+            replica: replica,
+            name: name.to_owned()
+        }
+    }
+
+    /// The sequence number of the last entry applied to the replica.
+    pub fn applied_seq(&self) -> Result<u64, error> {
+        read_seq(self.replica, APPLIED_KEY)
+    }
+
+    /// Record on the replica that every entry up to `seq` is applied,
+    /// e.g. after copying the primary as of that entry.
+    pub fn set_applied_seq(&self, seq: u64) -> Result<(), error> {
+        self.replica.put(APPLIED_KEY, encode_u64(seq), [])
+    }
+
+    /// Apply up to `limit` new entries, acknowledging them on the primary.
+    /// Returns the number of entries applied. Fails if the entries the
+    /// replica needs next were truncated; it must then be recopied.
+    pub fn poll(&self, limit: uint) -> Result<uint, error> {
+        let applied = match self.applied_seq() {
+            Ok(seq) => seq,
+            Err(err) => return Err(err)
+        };
+        let entries = match self.primary.read_change_log(applied, limit) {
+            Ok(entries) => entries,
+            Err(err) => return Err(err)
+        };
+        let mut last = applied;
+        for &(seq, ref rep) in entries.iter() {
+            if seq != last + 1 {
+                return Err(format!("change log entry {} is missing", last + 1));
+            }
+            let mut batch = WriteBatch::new();
+            match decode_batch(*rep, &mut batch as &mut WriteBatchVisitor) {
+                Ok(_) => {},
+                Err(err) => return Err(err)
+            }
+            batch.put(APPLIED_KEY, encode_u64(seq));
+            match self.replica.write(&batch, []) {
+                Ok(_) => {},
+                Err(err) => return Err(err)
+            }
+            last = seq;
+        }
+        if last != applied {
+            match self.primary.ack_change_log(self.name, last) {
+                Ok(_) => {},
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(entries.len())
+    }
+}
@@ -0,0 +1,96 @@
+//! Snappy decompression, for reading table blocks without libsnappy.
+
+use std::cmp;
+use std::vec;
+
+use super::error;
+use super::coding::get_varint32;
+
+static TAG_LITERAL: u8 = 0;
+static TAG_COPY1: u8 = 1;
+static TAG_COPY2: u8 = 2;
+
+// Little-endian integer of `n` bytes at `*pos`, advancing `*pos`.
+fn get_le(bytes: &[u8], pos: &mut uint, n: uint) -> Option<uint> {
+    if *pos + n > bytes.len() {
+        return None;
+    }
+    let mut v = 0u;
+    for i in range(0, n) {
+        v |= (bytes[*pos + i] as uint) << (8 * i);
+    }
+    *pos += n;
+    Some(v)
+}
+
+/// The length a compressed buffer decompresses to.
+pub fn decompressed_len(input: &[u8]) -> Result<uint, error> {
+    let mut pos = 0u;
+    match get_varint32(input, &mut pos) {
+        Some(len) => Ok(len as uint),
+        None => Err(~"snappy: bad length header")
+    }
+}
+
+pub fn decompress(input: &[u8]) -> Result<~[u8], error> {
+    let mut pos = 0u;
+    let len = match get_varint32(input, &mut pos) {
+        Some(len) => len as uint,
+        None => return Err(~"snappy: bad length header")
+    };
+    // The length comes from the input, so it only bounds the output.
+    let mut output = vec::with_capacity(cmp::min(len, input.len()));
+    while pos < input.len() {
+        let tag = input[pos];
+        pos += 1;
+        let kind = tag & 3;
+        if kind == TAG_LITERAL {
+            let mut n = (tag >> 2) as uint;
+            if n >= 60 {
+                n = match get_le(input, &mut pos, n - 59) {
+                    Some(n) => n,
+                    None => return Err(~"snappy: truncated literal length")
+                };
+            }
+            n += 1;
+            if n > input.len() - pos {
+                return Err(~"snappy: truncated literal");
+            }
+            if n > len - output.len() {
+                return Err(~"snappy: output longer than its length header");
+            }
+            output.push_all(input.slice(pos, pos + n));
+            pos += n;
+            continue;
+        }
+
+        let (n, offset) = if kind == TAG_COPY1 {
+            match get_le(input, &mut pos, 1) {
+                Some(low) => (4 + ((tag >> 2) & 7) as uint, ((tag >> 5) as uint) << 8 | low),
+                None => return Err(~"snappy: truncated copy")
+            }
+        } else {
+            let width = if kind == TAG_COPY2 { 2 } else { 4 };
+            match get_le(input, &mut pos, width) {
+                Some(offset) => (1 + (tag >> 2) as uint, offset),
+                None => return Err(~"snappy: truncated copy")
+            }
+        };
+        if offset == 0 || offset > output.len() {
+            return Err(format!("snappy: bad copy offset {}", offset));
+        }
+        if n > len - output.len() {
+            return Err(~"snappy: output longer than its length header");
+        }
+        // Copies may overlap their own output, so go byte by byte.
+        let start = output.len() - offset;
+        for i in range(0, n) {
+            let b = output[start + i];
+            output.push(b);
+        }
+    }
+    if output.len() != len {
+        return Err(format!("snappy: expected {} bytes, got {}", len, output.len()));
+    }
+    Ok(output)
+}
@@ -0,0 +1,143 @@
+//! Notification of writes.
+//!
+//! Writes made through a `DB` handle are published to its subscribers
+//! once they succeed. Keys the wrapper keeps for itself, such as merge
+//! operands or index entries, are not published. Publishing happens under the write lock, so changes
+//! arrive in commit order. A subscriber that falls more than its capacity
+//! behind is dropped rather than stalling writers: its subscription
+//! returns the changes already queued, then `None`.
+
+use extra::arc::MutexArc;
+
+use super::is_internal_key;
+
+pub static DEFAULT_CAPACITY: uint = 1024;
+
+#[deriving(Eq, Clone)]
+pub enum Change {
+    Put(~[u8], ~[u8]),
+    Delete(~[u8])
+}
+
+impl Change {
+    pub fn key<'a>(&'a self) -> &'a [u8] {
+        match *self {
+            Put(ref key, _) => key.as_slice(),
+            Delete(ref key) => key.as_slice()
+        }
+    }
+}
+
+/// The receiving end of `DB::subscribe`
+pub struct Subscription {
+    priv port: Port<Change>,
+    // Changes sent but not yet received, shared with the publisher.
+    priv pending: MutexArc<uint>
+}
+
+impl Subscription {
+    /// Wait for the next change. Returns `None` once the subscription has
+    /// been dropped by the publisher and its queue is drained.
+    pub fn recv(&self) -> Option<Change> {
+        let change = self.port.recv_opt();
+        if change.is_some() {
+            self.pending.access(|pending| *pending -= 1);
+        }
+        change
+    }
+
+    /// The next change, if one is queued.
+    pub fn try_recv(&self) -> Option<Change> {
+        let change = self.port.try_recv();
+        if change.is_some() {
+            self.pending.access(|pending| *pending -= 1);
+        }
+        change
+    }
+}
+
+struct Subscriber {
+    prefix: ~[u8],
+    chan: Chan<Change>,
+    pending: MutexArc<uint>,
+    capacity: uint
+}
+
+impl Subscriber {
+    // Returns false if the subscriber must be dropped.
+    fn send(&self, change: &Change) -> bool {
+        if !change.key().starts_with(self.prefix) {
+            return true;
+        }
+        let full = self.pending.access(|pending| {
+            if *pending >= self.capacity {
+                true
+            } else {
+                *pending += 1;
+                false
+            }
+        });
+        !full && self.chan.try_send(change.clone())
+    }
+}
+
+#[deriving(Clone)]
+pub struct Subscribers {
+    priv list: MutexArc<~[Subscriber]>
+}
+
+impl Subscribers {
+    pub fn new() -> Subscribers {
+        Subscribers {
+            list: MutexArc::new(~[])
+        }
+    }
+
+    pub fn subscribe(&self, prefix: &[u8], capacity: uint) -> Subscription {
+        let (port, chan) = Chan::new();
+        let pending = MutexArc::new(0u);
+        let subscriber = Subscriber {
+            prefix: prefix.to_owned(),
+            chan: chan,
+            pending: pending.clone(),
+            capacity: capacity
+        };
+        // Chan is not Freeze, so the safe accessor is unavailable; the
+        // list is only touched while the mutex is held.
+        unsafe {
+            self.list.unsafe_access(|list| list.push(subscriber));
+        }
+        Subscription {
+            port: port,
+            pending: pending
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        unsafe {
+            self.list.unsafe_access(|list| list.is_empty())
+        }
+    }
+
+    /// Send the updates of a committed write to the interested
+    /// subscribers, dropping those that are full or gone. Internal keys
+    /// are skipped.
+    pub fn publish(&self, updates: ~[(~[u8], Option<~[u8]>)]) {
+        let changes: ~[Change] = updates.move_iter().filter(|&(ref key, _)| {
+            !is_internal_key(*key)
+        }).map(|(key, value)| {
+            match value {
+                Some(value) => Put(key, value),
+                None => Delete(key)
+            }
+        }).collect();
+        if changes.is_empty() {
+            return;
+        }
+        unsafe {
+            self.list.unsafe_access(|list| {
+                list.retain(|subscriber| changes.iter().all(|change| subscriber.send(change)));
+            });
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Reading LevelDB table files (`.ldb`, `.sst`) without libleveldb.
+//!
+//! A table ends with a fixed-size footer pointing at the index block and
+//! the meta-index block. The index block maps the last key of each data
+//! block to its position; the meta-index block maps `filter.<policy>` to
+//! the filter block. Every block is followed by a compression type byte
+//! and the masked CRC-32C of the block and that byte. Blocks hold entries
+//! whose keys share a prefix with the previous key, except at restart
+//! points, whose offsets are listed at the end of the block. Keys of data
+//! blocks are internal keys: the user key followed by a fixed64 packing
+//! the sequence number and the value type.
+
+use std::str;
+
+use super::{error, read_file};
+use super::coding::{get_fixed32, get_fixed64, get_varint32, get_varint64};
+use super::crc32c;
+use super::snappy;
+
+pub static FOOTER_LEN: uint = 48;
+static MAGIC: u64 = 0xdb4775248b80fb57;
+static TRAILER_LEN: uint = 5;
+static NO_COMPRESSION: u8 = 0;
+static SNAPPY_COMPRESSION: u8 = 1;
+static FILTER_PREFIX: &'static str = "filter.";
+
+/// The position of a block in a table file
+#[deriving(Eq, Clone)]
+pub struct BlockHandle {
+    offset: u64,
+    size: u64
+}
+
+impl BlockHandle {
+    /// Decode a handle at `*pos`, advancing `*pos` past it.
+    pub fn decode(bytes: &[u8], pos: &mut uint) -> Result<BlockHandle, error> {
+        match (get_varint64(bytes, pos), get_varint64(bytes, pos)) {
+            (Some(offset), Some(size)) => Ok(BlockHandle { offset: offset, size: size }),
+            _ => Err(~"bad block handle")
+        }
+    }
+}
+
+pub struct Footer {
+    metaindex: BlockHandle,
+    index: BlockHandle
+}
+
+impl Footer {
+    pub fn decode(bytes: &[u8]) -> Result<Footer, error> {
+        if bytes.len() != FOOTER_LEN {
+            return Err(~"footer too short");
+        }
+        if get_fixed64(bytes.slice_from(FOOTER_LEN - 8)) != MAGIC {
+            return Err(~"bad table magic number");
+        }
+        let mut pos = 0u;
+        let metaindex = match BlockHandle::decode(bytes, &mut pos) {
+            Ok(handle) => handle,
+            Err(err) => return Err(err)
+        };
+        match BlockHandle::decode(bytes, &mut pos) {
+            Ok(index) => Ok(Footer { metaindex: metaindex, index: index }),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+#[deriving(Eq, Clone)]
+pub enum ValueType {
+    TypeDeletion,
+    TypeValue
+}
+
+/// A key of a data block as stored by LevelDB
+#[deriving(Eq, Clone)]
+pub struct InternalKey {
+    user_key: ~[u8],
+    sequence: u64,
+    value_type: ValueType
+}
+
+impl InternalKey {
+    pub fn decode(bytes: &[u8]) -> Result<InternalKey, error> {
+        if bytes.len() < 8 {
+            return Err(~"internal key too short");
+        }
+        let split = bytes.len() - 8;
+        let tag = get_fixed64(bytes.slice_from(split));
+        let value_type = match tag & 0xff {
+            0 => TypeDeletion,
+            1 => TypeValue,
+            other => return Err(format!("bad value type {}", other))
+        };
+        Ok(InternalKey {
+            user_key: bytes.slice_to(split).to_owned(),
+            sequence: tag >> 8,
+            value_type: value_type
+        })
+    }
+}
+
+/// A decoded block: its entries in order
+pub struct Block {
+    entries: ~[(~[u8], ~[u8])],
+    restarts: ~[u32]
+}
+
+impl Block {
+    /// Decode the entries of `data`, checking that every restart point
+    /// starts an entry with no shared prefix.
+    pub fn decode(data: &[u8]) -> Result<Block, error> {
+        if data.len() < 4 {
+            return Err(~"block too short");
+        }
+        let num_restarts = get_fixed32(data.slice_from(data.len() - 4)) as uint;
+        if num_restarts > (data.len() - 4) / 4 {
+            return Err(~"bad restart count");
+        }
+        let limit = data.len() - (num_restarts + 1) * 4;
+        let restarts: ~[u32] = range(0, num_restarts).map(|i| {
+            get_fixed32(data.slice_from(limit + 4 * i))
+        }).collect();
+
+        let mut entries = ~[];
+        let mut key: ~[u8] = ~[];
+        let mut pos = 0u;
+        let mut next_restart = 0u;
+        while pos < limit {
+            let entry_start = pos;
+            let (shared, non_shared, value_len) = match (get_varint32(data, &mut pos),
+                                                         get_varint32(data, &mut pos),
+                                                         get_varint32(data, &mut pos)) {
+                (Some(a), Some(b), Some(c)) => (a as uint, b as uint, c as uint),
+                _ => return Err(format!("bad entry header at offset {}", entry_start))
+            };
+            if shared > key.len() || non_shared > limit - pos || value_len > limit - pos - non_shared {
+                return Err(format!("bad entry at offset {}", entry_start));
+            }
+            if next_restart < restarts.len() && restarts[next_restart] as uint == entry_start {
+                if shared != 0 {
+                    return Err(format!("restart point {} shares a prefix", entry_start));
+                }
+                next_restart += 1;
+            }
+            key.truncate(shared);
+            key.push_all(data.slice(pos, pos + non_shared));
+            pos += non_shared;
+            entries.push((key.clone(), data.slice(pos, pos + value_len).to_owned()));
+            pos += value_len;
+        }
+        if next_restart != restarts.len() {
+            return Err(~"restart point not at an entry");
+        }
+        Ok(Block {
+            entries: entries,
+            restarts: restarts
+        })
+    }
+}
+
+/// A filter block: one filter per `2^base_lg` bytes of data blocks
+pub struct FilterBlock {
+    base_lg: u8,
+    filters: ~[~[u8]]
+}
+
+impl FilterBlock {
+    pub fn decode(data: &[u8]) -> Result<FilterBlock, error> {
+        if data.len() < 5 {
+            return Err(~"filter block too short");
+        }
+        let base_lg = data[data.len() - 1];
+        let array_start = get_fixed32(data.slice(data.len() - 5, data.len() - 1)) as uint;
+        if array_start > data.len() - 5 {
+            return Err(~"bad filter offset array");
+        }
+        let num = (data.len() - 5 - array_start) / 4;
+        let offsets: ~[uint] = range(0, num).map(|i| {
+            get_fixed32(data.slice_from(array_start + 4 * i)) as uint
+        }).collect();
+        let mut filters = ~[];
+        for i in range(0, num) {
+            let end = if i + 1 < num { offsets[i + 1] } else { array_start };
+            if offsets[i] > end || end > array_start {
+                return Err(format!("bad offset for filter {}", i));
+            }
+            filters.push(data.slice(offsets[i], end).to_owned());
+        }
+        Ok(FilterBlock {
+            base_lg: base_lg,
+            filters: filters
+        })
+    }
+}
+
+/// A table file read into memory
+pub struct Table {
+    priv contents: ~[u8],
+    footer: Footer,
+    index: Block,
+    metaindex: Block,
+    /// The filter policy name and filter block, if the table has one
+    filter: Option<(~str, FilterBlock)>
+}
+
+impl Table {
+    pub fn open(path: &Path) -> Result<Table, error> {
+        match read_file(path) {
+            Ok(contents) => Table::from_bytes(contents),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn from_bytes(contents: ~[u8]) -> Result<Table, error> {
+        if contents.len() < FOOTER_LEN {
+            return Err(~"file too short to be a table");
+        }
+        let footer = match Footer::decode(contents.slice_from(contents.len() - FOOTER_LEN)) {
+            Ok(footer) => footer,
+            Err(err) => return Err(err)
+        };
+        let index = match read_block(contents, &footer.index).and_then(|data| Block::decode(data)) {
+            Ok(block) => block,
+            Err(err) => return Err(format!("index block: {}", err))
+        };
+        let metaindex = match read_block(contents, &footer.metaindex)
+                                  .and_then(|data| Block::decode(data)) {
+            Ok(block) => block,
+            Err(err) => return Err(format!("meta-index block: {}", err))
+        };
+        let mut filter = None;
+        for &(ref key, ref value) in metaindex.entries.iter() {
+            if !key.starts_with(FILTER_PREFIX.as_bytes()) {
+                continue;
+            }
+            let name = match str::from_utf8_opt(key.slice_from(FILTER_PREFIX.len())) {
+                Some(name) => name.to_owned(),
+                None => return Err(~"filter policy name is not UTF-8")
+            };
+            let mut pos = 0u;
+            let block = BlockHandle::decode(*value, &mut pos)
+                .and_then(|handle| read_block(contents, &handle))
+                .and_then(|data| FilterBlock::decode(data));
+            match block {
+                Ok(block) => filter = Some((name, block)),
+                Err(err) => return Err(format!("filter block: {}", err))
+            }
+        }
+        Ok(Table {
+            contents: contents,
+            footer: footer,
+            index: index,
+            metaindex: metaindex,
+            filter: filter
+        })
+    }
+
+    /// The last key and handle of each data block, in order.
+    pub fn data_blocks(&self) -> Result<~[(~[u8], BlockHandle)], error> {
+        let mut blocks = ~[];
+        for &(ref key, ref value) in self.index.entries.iter() {
+            let mut pos = 0u;
+            match BlockHandle::decode(*value, &mut pos) {
+                Ok(handle) => blocks.push((key.clone(), handle)),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Read, verify and decode the block at `handle`.
+    pub fn read_block(&self, handle: &BlockHandle) -> Result<Block, error> {
+        read_block(self.contents, handle).and_then(|data| Block::decode(data))
+    }
+
+    /// Iterate over the entries of every data block. A block that fails
+    /// its checksum or does not decode yields one error, and iteration
+    /// resumes at the next block.
+    pub fn iter<'a>(&'a self) -> TableIterator<'a> {
+        TableIterator {
+            table: self,
+            blocks: self.data_blocks(),
+            block: 0,
+            entries: ~[],
+            entry: 0
+        }
+    }
+}
+
+// The contents of the block at `handle`, checked and decompressed.
+fn read_block(contents: &[u8], handle: &BlockHandle) -> Result<~[u8], error> {
+    // Handles come from the file, so check them without overflowing.
+    let len = contents.len() as u64;
+    if handle.offset > len || handle.size > len - handle.offset
+        || len - handle.offset - handle.size < TRAILER_LEN as u64 {
+        return Err(format!("block at {} extends past the end of the file", handle.offset));
+    }
+    let start = handle.offset as uint;
+    let end = start + handle.size as uint;
+    let compression = contents[end];
+    let expected = crc32c::unmask(get_fixed32(contents.slice(end + 1, end + TRAILER_LEN)));
+    if crc32c::value(contents.slice(start, end + 1)) != expected {
+        return Err(format!("checksum mismatch in block at {}", start));
+    }
+    let data = contents.slice(start, end);
+    if compression == NO_COMPRESSION {
+        Ok(data.to_owned())
+    } else if compression == SNAPPY_COMPRESSION {
+        snappy::decompress(data)
+    } else {
+        Err(format!("unknown compression type {} in block at {}", compression, start))
+    }
+}
+
+/// Iterator over the entries of a table's data blocks
+pub struct TableIterator<'a> {
+    priv table: &'a Table,
+    priv blocks: Result<~[(~[u8], BlockHandle)], error>,
+    priv block: uint,
+    priv entries: ~[(~[u8], ~[u8])],
+    priv entry: uint
+}
+
+impl<'a> Iterator<Result<(InternalKey, ~[u8]), error>> for TableIterator<'a> {
+    fn next(&mut self) -> Option<Result<(InternalKey, ~[u8]), error>> {
+        let num_blocks = match self.blocks {
+            Ok(ref blocks) => blocks.len(),
+            Err(ref err) => {
+                // A bad index yields its error once.
+                if self.block > 0 {
+                    return None;
+                }
+                self.block = 1;
+                return Some(Err(err.clone()));
+            }
+        };
+        while self.entry == self.entries.len() {
+            if self.block == num_blocks {
+                return None;
+            }
+            let handle = match self.blocks {
+                Ok(ref blocks) => {
+                    let (_, ref handle) = blocks[self.block];
+                    handle.clone()
+                },
+                Err(_) => fail!()
+            };
+            self.block += 1;
+            self.entry = 0;
+            match self.table.read_block(&handle) {
+                Ok(block) => self.entries = block.entries,
+                Err(err) => {
+                    self.entries = ~[];
+                    return Some(Err(err));
+                }
+            }
+        }
+        let (ref key, ref value) = self.entries[self.entry];
+        self.entry += 1;
+        Some(InternalKey::decode(*key).map(|key| (key, value.clone())))
+    }
+}
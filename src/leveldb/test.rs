@@ -1,9 +1,25 @@
 extern mod leveldb;
 
+use std::cast::transmute;
+use std::io::{Reader, Writer};
+use std::io::mem::{MemReader, MemWriter};
 use std::str::from_utf8;
 
-use leveldb::DB;
+use leveldb::{DB, WriteBatch, RangeIterator, Change, Put, Delete, Subscription};
 use leveldb::options;
+use leveldb::keys;
+use leveldb::transaction::Conflict;
+use leveldb::diff::{diff, Diff, OnlyInA, OnlyInB, Changed};
+use leveldb::merge::{U64Add, Lazy, encode_u64, decode_u64};
+use leveldb::index;
+use leveldb::index::{Index, Extractor};
+use leveldb::replication::Follower;
+use leveldb::dump::{Binary, HexCsv};
+use leveldb::crc32c;
+use leveldb::snappy;
+use leveldb::log::{LogReader, BLOCK_SIZE, Corrupt, Torn};
+use leveldb::manifest::{VersionEdit, Version};
+use leveldb::table::{Table, Block, BlockHandle, InternalKey, TypeValue, FOOTER_LEN};
 
 #[test]
 fn test_db_open() {
@@ -31,3 +47,819 @@ fn test_db_open() {
         },
     }
 }
+
+
+#[test]
+fn test_keys_order() {
+    let tuples = ~[
+        ~[keys::Str(~"acme"), keys::Int(-5), keys::UInt(1)],
+        ~[keys::Str(~"acme"), keys::Int(-1), keys::UInt(0)],
+        ~[keys::Str(~"acme"), keys::Int(3)],
+        ~[keys::Str(~"acme"), keys::Int(3), keys::UInt(7)],
+        ~[keys::Str(~"acme\x00"), keys::Float(-1.5)],
+        ~[keys::Str(~"acmf"), keys::Float(0.25)],
+    ];
+    let encoded: ~[~[u8]] = tuples.iter().map(|t| keys::encode(*t)).collect();
+    for i in range(1, encoded.len()) {
+        assert!(encoded[i - 1] < encoded[i]);
+    }
+    for (t, e) in tuples.iter().zip(encoded.iter()) {
+        assert_eq!(keys::decode(*e), Ok(t.clone()));
+    }
+
+    let (start, end) = keys::prefix_range([keys::Str(~"acme"), keys::Int(3)]);
+    assert!(start <= encoded[2] && encoded[2] < end);
+    assert!(start <= encoded[3] && encoded[3] < end);
+    assert!(encoded[4] >= end);
+}
+
+#[test]
+fn test_namespaces() {
+    let db = match DB::open("db_namespace", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let users = db.namespace("users").unwrap();
+    let orders = db.namespace("orders").unwrap();
+
+    let mut batch = WriteBatch::new();
+    users.batch_put(&mut batch, "alice".as_bytes(), "1".as_bytes());
+    orders.batch_put(&mut batch, "alice".as_bytes(), "2".as_bytes());
+    db.write(&batch, []).unwrap();
+
+    assert_eq!(users.get("alice".as_bytes(), []), Ok("1".as_bytes().to_owned()));
+    assert_eq!(orders.get("alice".as_bytes(), []), Ok("2".as_bytes().to_owned()));
+    let keys: ~[~[u8]] = users.iter([]).map(|(key, _)| key).collect();
+    assert_eq!(keys, ~["alice".as_bytes().to_owned()]);
+    assert!(db.namespaces().unwrap().contains(&~"orders"));
+    db.close();
+}
+
+#[test]
+fn test_transaction_conflict() {
+    let db = match DB::open("db_transaction", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let key = "balance".as_bytes();
+    db.put(key, "10".as_bytes(), []).unwrap();
+
+    let mut txn = db.transaction();
+    assert_eq!(txn.get(key), Ok(Some("10".as_bytes().to_owned())));
+    txn.put(key, "20".as_bytes());
+    // A write made after the transaction read the key.
+    db.put(key, "15".as_bytes(), []).unwrap();
+    assert_eq!(txn.commit([]), Err(Conflict));
+    assert_eq!(db.get(key, []), Ok("15".as_bytes().to_owned()));
+
+    let mut txn = db.transaction();
+    txn.get(key).unwrap();
+    txn.put(key, "20".as_bytes());
+    assert_eq!(txn.commit([]), Ok(()));
+    assert_eq!(db.get(key, []), Ok("20".as_bytes().to_owned()));
+    db.close();
+}
+
+// Increment the u64 values of `keys` together `times` times with `update`.
+fn increment_all(db: &DB, keys: &[&[u8]], times: uint) -> Result<(), ~str> {
+    for _ in range(0, times) {
+        let res = db.update(keys, 5000, [], |txn| {
+            for key in keys.iter() {
+                let n = match txn.get(*key) {
+                    Ok(value) => value.and_then(|value| decode_u64(value)).unwrap_or(0),
+                    Err(err) => return Err(err)
+                };
+                txn.put(*key, encode_u64(n + 1));
+            }
+            Ok(())
+        });
+        match res {
+            Ok(_) => {},
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_update() {
+    DB::destroy("db_update", []);
+    let db = match DB::open("db_update", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let a = "a".as_bytes();
+    let b = "b".as_bytes();
+    let res = db.update([a, b], 1000, [], |txn| {
+        txn.put(a, "1".as_bytes());
+        txn.put(b, "1".as_bytes());
+        Ok(())
+    });
+    assert_eq!(res, Ok(()));
+    assert_eq!(db.get(b, []), Ok("1".as_bytes().to_owned()));
+    // Nothing is written if the closure fails.
+    let res: Result<(), ~str> = db.update([a, b], 1000, [], |txn| {
+        txn.put(a, "2".as_bytes());
+        Err(~"abort")
+    });
+    assert_eq!(res, Err(~"abort"));
+    assert_eq!(db.get(a, []), Ok("1".as_bytes().to_owned()));
+
+    // Key locks are not reentrant, so a nested update of a key times out.
+    let res = db.update([a], 1000, [], |_| Ok(db.update([a], 50, [], |_| Ok(())).is_err()));
+    assert_eq!(res, Ok(true));
+
+    // Updates locking the same keys in opposite orders do not deadlock.
+    // `DB` is not `Send`; the other task is done before it is closed.
+    let c = "c".as_bytes();
+    let d = "d".as_bytes();
+    let db_addr: uint = unsafe { transmute(&*db) };
+    let (port, chan) = Chan::new();
+    spawn(proc() {
+        let db: &DB = unsafe { transmute(db_addr) };
+        chan.send(increment_all(db, [d, c], 100));
+    });
+    assert_eq!(increment_all(&*db, [c, d], 100), Ok(()));
+    assert_eq!(port.recv(), Ok(()));
+    assert_eq!(db.get_opt(c, []), Ok(Some(encode_u64(200))));
+    assert_eq!(db.get_opt(d, []), Ok(Some(encode_u64(200))));
+    db.close();
+}
+
+#[test]
+fn test_counters() {
+    let db = match DB::open("db_counter", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let hits = "hits".as_bytes();
+    let misses = "misses".as_bytes();
+    let start = db.increment(hits, 0, []).unwrap();
+    assert_eq!(db.increment(hits, 5, []), Ok(start + 5));
+    let values = db.increment_many([(hits, -2), (misses, 1), (hits, 1)], []).unwrap();
+    assert_eq!(values[0], start + 4);
+    assert_eq!(values[2], start + 4);
+    db.close();
+}
+
+#[test]
+fn test_compare_and_swap() {
+    DB::destroy("db_cas", []);
+    let db = match DB::open("db_cas", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let key = "owner".as_bytes();
+    let alice = "alice".as_bytes();
+    let bob = "bob".as_bytes();
+    assert_eq!(db.put_if_absent(key, alice, []), Ok(true));
+    assert_eq!(db.put_if_absent(key, bob, []), Ok(false));
+    assert_eq!(db.get(key, []), Ok(alice.to_owned()));
+
+    // A mismatch leaves the value alone.
+    assert_eq!(db.compare_and_swap(key, Some(bob), Some(alice), []), Ok(false));
+    assert_eq!(db.compare_and_swap(key, None, Some(bob), []), Ok(false));
+    assert_eq!(db.compare_and_swap(key, Some(alice), Some(bob), []), Ok(true));
+    assert_eq!(db.get(key, []), Ok(bob.to_owned()));
+
+    // Swapping to `None` deletes; expecting `None` then matches.
+    assert_eq!(db.compare_and_swap(key, Some(bob), None, []), Ok(true));
+    assert_eq!(db.get_opt(key, []), Ok(None));
+    assert_eq!(db.compare_and_swap(key, None, Some(alice), []), Ok(true));
+    assert_eq!(db.get(key, []), Ok(alice.to_owned()));
+    db.close();
+}
+
+#[test]
+fn test_multi_get() {
+    DB::destroy("db_multi_get", []);
+    let db = match DB::open("db_multi_get", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    db.put("a".as_bytes(), "1".as_bytes(), []).unwrap();
+    db.put("b".as_bytes(), "2".as_bytes(), []).unwrap();
+    db.put("c".as_bytes(), "".as_bytes(), []).unwrap();
+    let keys = ["c".as_bytes(), "x".as_bytes(), "a".as_bytes(), "c".as_bytes(), "b".as_bytes()];
+    assert_eq!(db.multi_get(keys, []), Ok(~[Some(~[]), None, Some("1".as_bytes().to_owned()),
+                                           Some(~[]), Some("2".as_bytes().to_owned())]));
+    assert_eq!(db.multi_get([], []), Ok(~[]));
+    db.close();
+}
+
+#[test]
+fn test_delete_range() {
+    DB::destroy("db_delete_range", []);
+    let db = match DB::open("db_delete_range", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    for i in range(0u, 100) {
+        db.put(format!("k{:03u}", i).as_bytes(), "v".as_bytes(), []).unwrap();
+    }
+    // Batches of 16 bytes hold four keys, so the range takes ten batches.
+    assert_eq!(db.delete_range("k010".as_bytes(), "k050".as_bytes(),
+                               [options::BATCH_BYTES(16)]), Ok(40));
+    let keys: ~[~[u8]] = db.iter([]).map(|(key, _)| key).collect();
+    assert_eq!(keys.len(), 60);
+    assert_eq!(db.get_opt("k009".as_bytes(), []), Ok(Some("v".as_bytes().to_owned())));
+    assert_eq!(db.get_opt("k010".as_bytes(), []), Ok(None));
+    assert_eq!(db.get_opt("k049".as_bytes(), []), Ok(None));
+    assert_eq!(db.get_opt("k050".as_bytes(), []), Ok(Some("v".as_bytes().to_owned())));
+    assert_eq!(db.delete_range("k010".as_bytes(), "k050".as_bytes(), [options::COMPACT]), Ok(0));
+    db.close();
+}
+
+#[test]
+fn test_lazy_merge() {
+    let mut db = match DB::open("db_lazy_merge", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    db.set_merge_operator(~U64Add, Lazy).unwrap();
+    let key = "visits".as_bytes();
+    db.delete(key, []).unwrap();
+    db.merge(key, encode_u64(2), []).unwrap();
+    db.merge(key, encode_u64(3), []).unwrap();
+    assert_eq!(db.get_opt(key, []), Ok(Some(encode_u64(5))));
+
+    // Plain writes replace the pending operands.
+    db.delete(key, []).unwrap();
+    assert_eq!(db.get_opt(key, []), Ok(None));
+    db.merge(key, encode_u64(1), []).unwrap();
+    db.put(key, encode_u64(10), []).unwrap();
+    assert_eq!(db.get_opt(key, []), Ok(Some(encode_u64(10))));
+
+    db.merge(key, encode_u64(4), []).unwrap();
+    db.collapse_merge_operands(key, []).unwrap();
+    db.merge(key, encode_u64(1), []).unwrap();
+    assert_eq!(db.get_opt(key, []), Ok(Some(encode_u64(15))));
+    db.close();
+}
+
+#[test]
+fn test_ttl() {
+    DB::destroy("db_ttl", []);
+    let db = match DB::open("db_ttl", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let ttl = db.ttl();
+    // A TTL of 0 expires at once.
+    ttl.put_with_ttl("a".as_bytes(), "1".as_bytes(), 0, []).unwrap();
+    ttl.put_with_ttl("b".as_bytes(), "2".as_bytes(), 3600, []).unwrap();
+    ttl.put("c".as_bytes(), "3".as_bytes(), []).unwrap();
+    // Rewritten around the view, as by a write racing the sweep between
+    // reading the index entry of "d" and locking the key.
+    ttl.put_with_ttl("d".as_bytes(), "4".as_bytes(), 0, []).unwrap();
+    db.put("d".as_bytes(), "5".as_bytes(), []).unwrap();
+
+    assert_eq!(ttl.get("a".as_bytes(), []), Ok(None));
+    assert_eq!(ttl.get("b".as_bytes(), []), Ok(Some("2".as_bytes().to_owned())));
+    let keys: ~[~[u8]] = ttl.iter([]).map(|(key, _)| key).collect();
+    assert_eq!(keys, ~["b".as_bytes().to_owned(), "c".as_bytes().to_owned()]);
+
+    assert_eq!(ttl.sweep(10), Ok(1));
+    assert_eq!(db.get_opt("a".as_bytes(), []), Ok(None));
+    assert_eq!(db.get_opt("d".as_bytes(), []), Ok(Some("5".as_bytes().to_owned())));
+    assert_eq!(ttl.get("b".as_bytes(), []), Ok(Some("2".as_bytes().to_owned())));
+    assert_eq!(ttl.sweep(10), Ok(0));
+    db.close();
+}
+
+// Indexes records by their whole value.
+struct ValueExtractor;
+
+impl Extractor for ValueExtractor {
+    fn extract(&self, _key: &[u8], value: &[u8]) -> ~[~[u8]] {
+        ~[value.to_owned()]
+    }
+}
+
+#[test]
+fn test_index() {
+    DB::destroy("db_index", []);
+    let mut db = match DB::open("db_index", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    db.add_index(Index::new("city", ~ValueExtractor));
+    let alice = "alice".as_bytes();
+    let paris = "paris".as_bytes();
+    let rome = "rome".as_bytes();
+    let entries = |db: &DB| -> ~[~[u8]] {
+        db.iter([]).map(|(key, _)| key).filter(|key| index::is_entry_key(*key)).collect()
+    };
+
+    db.put(alice, paris, []).unwrap();
+    assert_eq!(db.index_keys("city", paris, []), Ok(~[alice.to_owned()]));
+    assert_eq!(entries(&*db).len(), 1);
+
+    // Overwriting the record replaces its entry.
+    db.put(alice, rome, []).unwrap();
+    assert_eq!(db.index_keys("city", paris, []), Ok(~[]));
+    assert_eq!(db.index_lookup("city", rome, []), Ok(~[(alice.to_owned(), rome.to_owned())]));
+    assert_eq!(entries(&*db).len(), 1);
+
+    // Deleting it removes the entry.
+    db.delete(alice, []).unwrap();
+    assert_eq!(db.index_keys("city", rome, []), Ok(~[]));
+    assert!(entries(&*db).is_empty());
+
+    // A later write to a key in the same batch shadows the earlier one.
+    let mut batch = WriteBatch::new();
+    batch.put(alice, paris);
+    batch.put(alice, rome);
+    db.write(&batch, []).unwrap();
+    assert_eq!(db.index_keys("city", paris, []), Ok(~[]));
+    assert_eq!(db.index_keys("city", rome, []), Ok(~[alice.to_owned()]));
+    assert_eq!(entries(&*db).len(), 1);
+    db.close();
+}
+
+#[test]
+fn test_subscribe() {
+    DB::destroy("db_subscribe", []);
+    let mut db = match DB::open("db_subscribe", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    db.set_merge_operator(~U64Add, Lazy).unwrap();
+    // An empty prefix would also match any internal key that leaked.
+    let all = db.subscribe([]);
+    let some = db.subscribe("s:".as_bytes());
+
+    db.put("s:a".as_bytes(), "1".as_bytes(), []).unwrap();
+    db.put("t:a".as_bytes(), "2".as_bytes(), []).unwrap();
+    db.delete("s:a".as_bytes(), []).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put("s:b".as_bytes(), "3".as_bytes());
+    batch.delete("s:c".as_bytes());
+    db.write(&batch, []).unwrap();
+    // Merge operands and expiry entries are internal; values written with
+    // a TTL are published without their header.
+    db.merge("s:n".as_bytes(), encode_u64(1), []).unwrap();
+    db.ttl().put_with_ttl("s:t".as_bytes(), "4".as_bytes(), 3600, []).unwrap();
+
+    let changes = |subscription: &Subscription| -> ~[Change] {
+        let mut changes = ~[];
+        loop {
+            match subscription.try_recv() {
+                Some(change) => changes.push(change),
+                None => return changes
+            }
+        }
+    };
+    let s_changes = ~[Put("s:a".as_bytes().to_owned(), "1".as_bytes().to_owned()),
+                      Delete("s:a".as_bytes().to_owned()),
+                      Put("s:b".as_bytes().to_owned(), "3".as_bytes().to_owned()),
+                      Delete("s:c".as_bytes().to_owned()),
+                      Put("s:t".as_bytes().to_owned(), "4".as_bytes().to_owned())];
+    assert_eq!(changes(&some), s_changes.clone());
+    let mut all_changes = s_changes;
+    all_changes.insert(1, Put("t:a".as_bytes().to_owned(), "2".as_bytes().to_owned()));
+    assert_eq!(changes(&all), all_changes);
+    db.close();
+}
+
+#[test]
+fn test_change_log_follower() {
+    for name in ["db_primary", "db_replica", "db_late_replica"].iter() {
+        DB::destroy(*name, []);
+    }
+    let open = |name: &str| match DB::open(name, [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let mut primary = open("db_primary");
+    primary.enable_change_log().unwrap();
+    primary.put("a".as_bytes(), "1".as_bytes(), []).unwrap();
+    primary.put("b".as_bytes(), "2".as_bytes(), []).unwrap();
+    assert_eq!(primary.change_log_seq(), Some(2));
+
+    let replica = open("db_replica");
+    assert_eq!(Follower::new(&*primary, &*replica, "replica").poll(10), Ok(2));
+    replica.close();
+
+    // The follower resumes after the replica is reopened.
+    primary.put("c".as_bytes(), "3".as_bytes(), []).unwrap();
+    let replica = open("db_replica");
+    let follower = Follower::new(&*primary, &*replica, "replica");
+    assert_eq!(follower.applied_seq(), Ok(2));
+    assert_eq!(follower.poll(10), Ok(1));
+    assert_eq!(replica.get("a".as_bytes(), []), Ok("1".as_bytes().to_owned()));
+    assert_eq!(replica.get("c".as_bytes(), []), Ok("3".as_bytes().to_owned()));
+    replica.close();
+
+    // Acknowledged entries are truncated; a new follower then cannot catch up.
+    assert_eq!(primary.truncate_change_log(), Ok(3));
+    let late_replica = open("db_late_replica");
+    assert!(Follower::new(&*primary, &*late_replica, "late").poll(10).is_err());
+    late_replica.close();
+    primary.close();
+}
+
+#[test]
+fn test_backup() {
+    for name in ["db_backup_source", "db_backup"].iter() {
+        DB::destroy(*name, []);
+    }
+    let open = |name: &str| match DB::open(name, [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let mut source = open("db_backup_source");
+    source.enable_change_log().unwrap();
+    source.put("k:a".as_bytes(), "1".as_bytes(), []).unwrap();
+    source.put("k:b".as_bytes(), "2".as_bytes(), []).unwrap();
+    assert_eq!(source.backup_to("db_backup", |_, _| {}), Ok(2));
+
+    source.put("k:c".as_bytes(), "3".as_bytes(), []).unwrap();
+    source.delete("k:a".as_bytes(), []).unwrap();
+    assert_eq!(source.backup_incremental("db_backup", |_| {}), Ok(2));
+
+    let backup = open("db_backup");
+    let records = |db: &DB| -> ~[(~[u8], ~[u8])] {
+        db.iter([]).filter(|&(ref key, _)| key.starts_with("k:".as_bytes())).collect()
+    };
+    let diffs: ~[Diff] = diff(records(&*source).move_iter(), records(&*backup).move_iter()).collect();
+    assert_eq!(diffs, ~[]);
+    assert_eq!(backup.get_opt("k:a".as_bytes(), []), Ok(None));
+    assert_eq!(backup.get_opt("k:c".as_bytes(), []), Ok(Some("3".as_bytes().to_owned())));
+    backup.close();
+    source.close();
+}
+
+#[test]
+fn test_dump_load() {
+    for name in ["db_dump_source", "db_dump_binary", "db_dump_hex"].iter() {
+        DB::destroy(*name, []);
+    }
+    let open = |name: &str| match DB::open(name, [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let source = open("db_dump_source");
+    source.put("a".as_bytes(), "1".as_bytes(), []).unwrap();
+    source.put(bytes!("b\x00\xff"), bytes!("\x00\n,\""), []).unwrap();
+    let entries: ~[(~[u8], ~[u8])] = source.iter([]).collect();
+
+    for &(name, format) in [("db_dump_binary", Binary), ("db_dump_hex", HexCsv)].iter() {
+        let mut writer = MemWriter::new();
+        assert_eq!(source.dump(&mut writer as &mut Writer, format, None), Ok(2));
+        let dump = writer.inner();
+        let target = open(name);
+        let mut reader = MemReader::new(dump.clone());
+        assert_eq!(target.load(&mut reader as &mut Reader, format), Ok(2));
+        let loaded: ~[(~[u8], ~[u8])] = target.iter([]).collect();
+        assert_eq!(loaded, entries);
+
+        if format == Binary {
+            let mut reader = MemReader::new(dump.slice_to(dump.len() - 3).to_owned());
+            assert!(target.load(&mut reader as &mut Reader, format).is_err());
+        }
+        target.close();
+    }
+    source.close();
+}
+
+#[test]
+fn test_crc32c() {
+    // Vectors from RFC 3720 and LevelDB's crc32c_test.
+    assert_eq!(crc32c::value("123456789".as_bytes()), 0xe3069283);
+    assert_eq!(crc32c::value([0u8, ..32]), 0x8a9136aa);
+    assert_eq!(crc32c::value([0xffu8, ..32]), 0x62a8ab43);
+    let ascending: ~[u8] = range(0u8, 32).collect();
+    assert_eq!(crc32c::value(ascending), 0x46dd794e);
+    assert_eq!(crc32c::extend(crc32c::value("hello ".as_bytes()), "world".as_bytes()),
+               crc32c::value("hello world".as_bytes()));
+    let crc = crc32c::value("foo".as_bytes());
+    assert!(crc32c::mask(crc) != crc);
+    assert_eq!(crc32c::unmask(crc32c::mask(crc)), crc);
+}
+
+#[test]
+fn test_snappy() {
+    // A literal "abc", then a copy of 6 bytes from 3 back, overlapping itself.
+    let compressed = [9u8, 0x08, 'a' as u8, 'b' as u8, 'c' as u8, 0x09, 0x03];
+    assert_eq!(snappy::decompressed_len(compressed), Ok(9));
+    assert_eq!(snappy::decompress(compressed), Ok("abcabcabc".as_bytes().to_owned()));
+
+    // Length header disagreeing with the contents.
+    assert!(snappy::decompress([8u8, 0x08, 'a' as u8, 'b' as u8, 'c' as u8, 0x09, 0x03]).is_err());
+    assert!(snappy::decompress([0xffu8, 0xff, 0xff, 0xff, 0x0f, 0x00, 'a' as u8]).is_err());
+    // A copy reaching before the start of the output.
+    assert!(snappy::decompress([9u8, 0x08, 'a' as u8, 'b' as u8, 'c' as u8, 0x09, 0x04]).is_err());
+    // A literal running past the end of the input.
+    assert!(snappy::decompress([9u8, 0x20, 'a' as u8]).is_err());
+}
+
+fn fixed32(n: u32) -> ~[u8] {
+    ~[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+fn varint(n: u64) -> ~[u8] {
+    let mut n = n;
+    let mut buf = ~[];
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+    buf
+}
+
+// A table key: `user_key` with the tag of a value at sequence `seq`.
+fn internal_key(user_key: &str, seq: u64) -> ~[u8] {
+    let mut key = user_key.as_bytes().to_owned();
+    let tag = seq << 8 | 1;
+    key.push_all(fixed32(tag as u32));
+    key.push_all(fixed32((tag >> 32) as u32));
+    key
+}
+
+// A block of entries sharing no prefix, with the given restart offsets.
+fn table_block(entries: &[(~[u8], ~[u8])], restarts: &[u32]) -> ~[u8] {
+    let mut block = ~[];
+    for &(ref key, ref value) in entries.iter() {
+        block.push(0u8);
+        block.push_all(varint(key.len() as u64));
+        block.push_all(varint(value.len() as u64));
+        block.push_all(*key);
+        block.push_all(*value);
+    }
+    for &restart in restarts.iter() {
+        block.push_all(fixed32(restart));
+    }
+    block.push_all(fixed32(restarts.len() as u32));
+    block
+}
+
+// Append `block` and its uncompressed trailer to `table` and return its
+// encoded handle.
+fn append_block(table: &mut ~[u8], block: &[u8]) -> ~[u8] {
+    let mut handle = varint(table.len() as u64);
+    handle.push_all(varint(block.len() as u64));
+    table.push_all(block);
+    let crc = crc32c::mask(crc32c::extend(crc32c::value(block), [0u8]));
+    table.push(0u8);
+    table.push_all(fixed32(crc));
+    handle
+}
+
+// A table holding `data` as its only data block, whose last key is "b".
+fn table_bytes(data: &[u8]) -> ~[u8] {
+    let mut table = ~[];
+    let data_handle = append_block(&mut table, data);
+    let mut footer = append_block(&mut table, table_block([], []));
+    let index = table_block([(internal_key("b", 2), data_handle)], [0]);
+    footer.push_all(append_block(&mut table, index));
+    footer.grow(FOOTER_LEN - 8 - footer.len(), &0u8);
+    footer.push_all([0x57u8, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0x47, 0xdb]);
+    table.push_all(footer);
+    table
+}
+
+#[test]
+fn test_table() {
+    let data = table_block([(internal_key("a", 1), "1".as_bytes().to_owned()),
+                            (internal_key("b", 2), "2".as_bytes().to_owned())], [0]);
+    let bytes = table_bytes(data);
+    let table = Table::from_bytes(bytes.clone()).unwrap();
+    let entries: ~[Result<(InternalKey, ~[u8]), _>] = table.iter().collect();
+    assert_eq!(entries, ~[
+        Ok((InternalKey { user_key: "a".as_bytes().to_owned(), sequence: 1, value_type: TypeValue },
+            "1".as_bytes().to_owned())),
+        Ok((InternalKey { user_key: "b".as_bytes().to_owned(), sequence: 2, value_type: TypeValue },
+            "2".as_bytes().to_owned()))
+    ]);
+    assert!(table.read_block(&BlockHandle { offset: bytes.len() as u64, size: 1 }).is_err());
+    assert!(table.read_block(&BlockHandle { offset: 0, size: !0u64 }).is_err());
+
+    // Truncated files are errors, not failures.
+    for &len in [0u, 1, FOOTER_LEN, bytes.len() / 2, bytes.len() - FOOTER_LEN, bytes.len() - 1].iter() {
+        assert!(Table::from_bytes(bytes.slice_to(len).to_owned()).is_err());
+    }
+    // So is a block cut short of its restart array, or whose restart
+    // points lie past its end or miss its entries.
+    assert!(Block::decode([0u8, 0]).is_err());
+    assert!(Block::decode(fixed32(0xffffffff)).is_err());
+    assert!(Block::decode(table_block([(~[1u8], ~[2u8])], [200])).is_err());
+    assert!(Block::decode(table_block([(~[1u8], ~[2u8])], [1])).is_err());
+    assert!(Block::decode(data.slice_from(1)).is_err());
+
+    // A data block with a bad restart point, or a bad checksum, yields
+    // one error.
+    let bad = table_block([(internal_key("b", 2), "2".as_bytes().to_owned())], [200]);
+    let table = Table::from_bytes(table_bytes(bad)).unwrap();
+    let entries: ~[Result<(InternalKey, ~[u8]), _>] = table.iter().collect();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].is_err());
+    let mut damaged = bytes.clone();
+    damaged[3] ^= 1;
+    let table = Table::from_bytes(damaged).unwrap();
+    let entries: ~[Result<(InternalKey, ~[u8]), _>] = table.iter().collect();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].is_err());
+}
+
+// A log fragment of type `kind` (1 full, 2 first, 3 middle, 4 last).
+fn log_fragment(kind: u8, data: &[u8]) -> ~[u8] {
+    let crc = crc32c::mask(crc32c::extend(crc32c::value([kind]), data));
+    let mut fragment = ~[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8,
+                         data.len() as u8, (data.len() >> 8) as u8, kind];
+    fragment.push_all(data);
+    fragment
+}
+
+#[test]
+fn test_log_reader() {
+    let mut log = log_fragment(1, "a".as_bytes());
+    log.push_all(log_fragment(2, "bc".as_bytes()));
+    log.push_all(log_fragment(3, "de".as_bytes()));
+    log.push_all(log_fragment(4, "f".as_bytes()));
+    let records: ~[Result<(u64, ~[u8]), _>] = LogReader::from_bytes(log.clone()).collect();
+    assert_eq!(records, ~[Ok((0, "a".as_bytes().to_owned())),
+                          Ok((8, "bcdef".as_bytes().to_owned()))]);
+
+    // A record whose last fragment was never written is torn.
+    let mut torn = log.clone();
+    torn.push_all(log_fragment(2, "gh".as_bytes()));
+    let records: ~[Result<(u64, ~[u8]), _>] = LogReader::from_bytes(torn).collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[2], Err(Torn(34)));
+    // So is a header cut short.
+    let mut torn = log.clone();
+    torn.push_all([0u8, 0, 0]);
+    let records: ~[Result<(u64, ~[u8]), _>] = LogReader::from_bytes(torn).collect();
+    assert_eq!(records[2], Err(Torn(34)));
+
+    // A checksum mismatch drops the rest of the block; reading resumes
+    // with the next one.
+    let mut damaged = log_fragment(1, "x".as_bytes());
+    damaged[7] = 'y' as u8;
+    damaged.grow(BLOCK_SIZE - damaged.len(), &0u8);
+    damaged.push_all(log_fragment(1, "z".as_bytes()));
+    let records: ~[Result<(u64, ~[u8]), _>] = LogReader::from_bytes(damaged).collect();
+    assert_eq!(records, ~[Err(Corrupt(0, BLOCK_SIZE, ~"checksum mismatch")),
+                          Ok((BLOCK_SIZE as u64, "z".as_bytes().to_owned()))]);
+}
+
+#[test]
+fn test_version_edit() {
+    let mut bytes = ~[1u8, 26];
+    bytes.push_all("leveldb.BytewiseComparator".as_bytes());
+    // Log number 5, last sequence 300.
+    bytes.push_all([2u8, 5, 4, 0xac, 0x02]);
+    // File 9 of 2000 bytes at level 1, from ("a", 3) to ("b", 4).
+    bytes.push_all([7u8, 1, 9, 0xd0, 0x0f]);
+    bytes.push_all([9u8, 'a' as u8, 1, 3, 0, 0, 0, 0, 0, 0]);
+    bytes.push_all([9u8, 'b' as u8, 1, 4, 0, 0, 0, 0, 0, 0]);
+    let edit = VersionEdit::decode(bytes).unwrap();
+    assert_eq!(edit.comparator, Some(~"leveldb.BytewiseComparator"));
+    assert_eq!(edit.log_number, Some(5));
+    assert_eq!(edit.last_sequence, Some(300));
+    assert_eq!(edit.new_files.len(), 1);
+    let (level, ref file) = edit.new_files[0];
+    assert_eq!(level, 1);
+    assert_eq!((file.number, file.size), (9, 2000));
+    assert_eq!(file.smallest, InternalKey { user_key: ~['a' as u8], sequence: 3,
+                                            value_type: TypeValue });
+    assert_eq!(file.largest.user_key, ~['b' as u8]);
+
+    let mut version = Version::new();
+    version.apply(&edit);
+    assert_eq!(version.files[1].len(), 1);
+    // Deleting the file empties the level again.
+    version.apply(&VersionEdit::decode([6u8, 1, 9]).unwrap());
+    assert!(version.files[1].is_empty());
+
+    // Truncated or malformed edits are errors, not failures.
+    assert!(VersionEdit::decode(bytes.slice_to(bytes.len() - 1)).is_err());
+    assert!(VersionEdit::decode([1u8, 2, 0xff, 0xfe]).is_err());
+    assert!(VersionEdit::decode([7u8, 7]).is_err());
+}
+
+#[test]
+fn test_verify() {
+    DB::destroy("db_verify", []);
+    let db = match DB::open("db_verify", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    for i in range(0u, 100) {
+        db.put(format!("k{:03u}", i).as_bytes(), "value".as_bytes(), []).unwrap();
+    }
+    // Read back from a table file as well as from the memtable.
+    db.compact_range(None, None);
+    db.put("z".as_bytes(), "last".as_bytes(), []).unwrap();
+
+    let report = db.verify(None);
+    assert!(report.is_ok());
+    assert_eq!((report.entries, report.bytes), (101, 100 * (4 + 5) + 1 + 4));
+    let report = db.verify(Some(("k010".as_bytes(), "k020".as_bytes())));
+    assert!(report.is_ok());
+    assert_eq!((report.entries, report.bytes), (10, 10 * (4 + 5)));
+    db.close();
+}
+
+#[test]
+fn test_diff() {
+    let entries = |pairs: &[(&str, &str)]| -> ~[(~[u8], ~[u8])] {
+        pairs.iter().map(|&(k, v)| (k.as_bytes().to_owned(), v.as_bytes().to_owned())).collect()
+    };
+    let a = entries([("a", "1"), ("b", "2"), ("d", "4"), ("e", "5")]);
+    let b = entries([("b", "2"), ("c", "3"), ("d", "5")]);
+    let diffs: ~[_] = diff(a.move_iter(), b.move_iter()).collect();
+    assert_eq!(diffs, ~[
+        OnlyInA("a".as_bytes().to_owned(), "1".as_bytes().to_owned()),
+        OnlyInB("c".as_bytes().to_owned(), "3".as_bytes().to_owned()),
+        Changed("d".as_bytes().to_owned(), "4".as_bytes().to_owned(), "5".as_bytes().to_owned()),
+        OnlyInA("e".as_bytes().to_owned(), "5".as_bytes().to_owned()),
+    ]);
+}
+
+#[test]
+fn test_merkle() {
+    for name in ["db_merkle_a", "db_merkle_b"].iter() {
+        DB::destroy(*name, []);
+    }
+    let a = match DB::open("db_merkle_a", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    let b = match DB::open("db_merkle_b", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    for i in range(0u, 1000) {
+        let key = format!("k{:04u}", i);
+        let value = format!("{:0100u}", i * 7919);
+        a.put(key.as_bytes(), value.as_bytes(), []).unwrap();
+        b.put(key.as_bytes(), value.as_bytes(), []).unwrap();
+    }
+    b.put("k0500".as_bytes(), "changed".as_bytes(), []).unwrap();
+    // Table files give the layout sizes to split on.
+    a.compact_range(None, None);
+    b.compact_range(None, None);
+
+    let tree = a.merkle_tree([], None, 16 * 1024).unwrap();
+    assert!(tree.diff(&a.merkle_tree_like(&tree).unwrap()).unwrap().is_empty());
+    let ranges = tree.diff(&b.merkle_tree_like(&tree).unwrap()).unwrap();
+    assert_eq!(ranges.len(), 1);
+    {
+        let key = "k0500".as_bytes();
+        let (ref start, ref end) = ranges[0];
+        assert!(start.as_slice() <= key);
+        match *end {
+            Some(ref end) => assert!(key < end.as_slice()),
+            None => {}
+        }
+        let end = end.as_ref().map(|end| end.as_slice());
+        assert!(a.range_digest(*start, end).unwrap() != b.range_digest(*start, end).unwrap());
+    }
+
+    assert_eq!(b.repair_ranges(&a, ranges), Ok(1));
+    assert_eq!(b.get_opt("k0500".as_bytes(), []), a.get_opt("k0500".as_bytes(), []));
+    assert!(tree.diff(&b.merkle_tree_like(&tree).unwrap()).unwrap().is_empty());
+    a.close();
+    b.close();
+}
+
+fn scan_keys(entries: &mut RangeIterator) -> ~[~[u8]] {
+    entries.by_ref().map(|(key, _)| key).collect()
+}
+
+fn concat(a: ~[~[u8]], b: ~[~[u8]]) -> ~[~[u8]] {
+    let mut a = a;
+    a.push_all_move(b);
+    a
+}
+
+#[test]
+fn test_par_scan() {
+    DB::destroy("db_par_scan", []);
+    let db = match DB::open("db_par_scan", [options::CREATE_IF_MISSING]) {
+        Ok(db) => db,
+        Err(err) => fail!(err)
+    };
+    for i in range(0u, 1000) {
+        let value = format!("{:0100u}", i * 7919);
+        db.put(format!("k{:04u}", i).as_bytes(), value.as_bytes(), []).unwrap();
+    }
+    db.compact_range(None, None);
+    assert!(db.partitions([], None, 4).len() > 1);
+
+    let parallel = db.par_scan([], None, 4, scan_keys, |a, b| concat(a, b)).unwrap();
+    let single = db.par_scan([], None, 1, scan_keys, |a, b| concat(a, b)).unwrap();
+    let keys: ~[~[u8]] = db.iter([]).map(|(key, _)| key).collect();
+    assert_eq!(parallel.len(), 1000);
+    assert_eq!(parallel, single);
+    assert_eq!(parallel, keys);
+
+    // Partitions of a bounded range stay within it.
+    let bounded = db.par_scan("k0100".as_bytes(), Some("k0200".as_bytes()), 4,
+                              scan_keys, |a, b| concat(a, b)).unwrap();
+    assert_eq!(bounded, keys.slice(100, 200).to_owned());
+    db.close();
+}
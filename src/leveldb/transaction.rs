@@ -0,0 +1,146 @@
+//! Optimistic transactions.
+//!
+//! A `Transaction` reads from a snapshot taken when it begins and buffers
+//! its writes. On commit, every key it read is read again under the
+//! database's write lock; if any of them changed since the snapshot the
+//! commit fails with `Conflict` and nothing is written. Otherwise the
+//! buffered writes are applied as one batch before the lock is released.
+//!
+//! `DB::update` is the pessimistic counterpart: it locks the named keys
+//! up front, so its transaction commits without validation.
+
+use std::hashmap::HashMap;
+
+use extra::treemap::TreeMap;
+
+use super::{DB, Snapshot, WriteBatch, error};
+use super::options::WriteOption;
+
+#[deriving(Eq)]
+pub enum TransactionError {
+    /// A key read by the transaction was modified before it committed.
+    Conflict,
+    /// LevelDB reported an error.
+    Failed(error)
+}
+
+pub struct Transaction<'r> {
+    priv db: &'r DB,
+    priv snapshot: Snapshot<'r>,
+    // The value of each key as first read from the snapshot.
+    priv reads: HashMap<~[u8], Option<~[u8]>>,
+    // Buffered writes; `None` is a delete.
+    priv writes: TreeMap<~[u8], Option<~[u8]>>
+}
+
+impl DB {
+    /// Begin an optimistic transaction reading from a new snapshot.
+    pub fn transaction<'r>(&'r self) -> Transaction<'r> {
+        Transaction {
+            db: self,
+            snapshot: self.snapshot(),
+            reads: HashMap::new(),
+            writes: TreeMap::new()
+        }
+    }
+
+    /// Lock `keys`, run `f` on a transaction reading from a snapshot taken
+    /// once the locks are held, and commit its writes if `f` succeeds.
+    ///
+    /// Fails without calling `f` if the locks cannot be taken within
+    /// `timeout_ms` milliseconds. Plain `put`, `delete` and `write` do
+    /// not take key locks.
+    ///
+    /// Key locks are not reentrant, and unrelated keys may share a lock.
+    /// `f` must therefore not call `increment`, `compare_and_swap`,
+    /// `merge`, `update` or `Ttl` writes, which lock their keys: they would
+    /// wait for the locks held here and fail once their timeout expires.
+    /// Make those changes through the transaction instead.
+    pub fn update<'r, T>(&'r self, keys: &[&[u8]], timeout_ms: u64, options: &[WriteOption],
+                         f: |&mut Transaction<'r>| -> Result<T, error>) -> Result<T, error> {
+        let _guard = match self.key_locks.lock_within(keys, timeout_ms) {
+            Ok(guard) => guard,
+            Err(err) => return Err(err)
+        };
+        let mut txn = self.transaction();
+        match f(&mut txn) {
+            Ok(res) => match txn.apply(options) {
+                Ok(_) => Ok(res),
+                Err(err) => Err(err)
+            },
+            Err(err) => Err(err)
+        }
+    }
+}
+
+impl<'r> Transaction<'r> {
+    /// Read `key`, seeing this transaction's own writes.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<~[u8]>, error> {
+        let key = key.to_owned();
+        match self.writes.find(&key) {
+            Some(value) => return Ok(value.clone()),
+            None => {}
+        }
+        match self.reads.find(&key) {
+            Some(value) => return Ok(value.clone()),
+            None => {}
+        }
+        match self.snapshot.get_opt(key, []) {
+            Ok(value) => {
+                self.reads.insert(key, value.clone());
+                Ok(value)
+            },
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_owned(), Some(value.to_owned()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_owned(), None);
+    }
+
+    fn batch(&self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.iter() {
+            match *value {
+                Some(ref value) => batch.put(*key, *value),
+                None => batch.delete(*key)
+            }
+        }
+        batch
+    }
+
+    // Apply the buffered writes without validating the reads.
+    fn apply(self, options: &[WriteOption]) -> Result<(), error> {
+        self.db.write(&self.batch(), options)
+    }
+
+    /// Validate the keys read and apply the buffered writes atomically.
+    pub fn commit(self, options: &[WriteOption]) -> Result<(), TransactionError> {
+        let batch = self.batch();
+        let Transaction { db, snapshot: _snapshot, reads, writes: _ } = self;
+        db.write_lock.lock(|| {
+            let mut conflict = false;
+            for (key, expected) in reads.iter() {
+                match db.get_opt(*key, []) {
+                    Ok(current) => if current != *expected {
+                        conflict = true;
+                        break;
+                    },
+                    Err(err) => return Err(Failed(err))
+                }
+            }
+            if conflict {
+                Err(Conflict)
+            } else {
+                match db.write_unlocked(&batch, options) {
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(Failed(err))
+                }
+            }
+        })
+    }
+}
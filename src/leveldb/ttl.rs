@@ -0,0 +1,315 @@
+//! Keys that expire.
+//!
+//! Values written through a `Ttl` view carry a header holding their expiry
+//! time, and reads through the view hide expired entries. For every value
+//! with an expiry an entry is also kept in an index ordered by expiry time,
+//! which lets `sweep` find expired keys without scanning the keyspace.
+//! Keys read through the view must have been written through it.
+
+use std::io::timer;
+use std::task;
+
+use extra::time::get_time;
+
+use super::{DB, DBIterator, WriteBatch, error};
+use super::cleveldb::leveldb_iter_destroy;
+use super::locks::LOCK_TIMEOUT_MS;
+use super::coding::{decode_u64, encode_u64};
+use super::options::{ReadOption, WriteOption};
+
+static HEADER_TAG: u8 = 0x01;
+static HEADER_LEN: uint = 9;
+static INDEX_PREFIX: &'static [u8] = bytes!("\x00ttl\x00");
+static INDEX_END: &'static [u8] = bytes!("\x00ttl\x01");
+
+/// A view of a database whose values may expire
+pub struct Ttl<'r> {
+    priv db: &'r DB
+}
+
+/// A background task deleting expired keys, stopped when dropped
+pub struct Sweeper<'r> {
+    priv db: &'r DB,
+    priv stop: Chan<()>,
+    priv done: Port<()>
+}
+
+fn now() -> u64 {
+    get_time().sec as u64
+}
+
+// Expiry times are in seconds since the epoch; 0 means never.
+fn encode_value(value: &[u8], expiry: u64) -> ~[u8] {
+    let mut bytes = ~[HEADER_TAG];
+    bytes.push_all(encode_u64(expiry));
+    bytes.push_all(value);
+    bytes
+}
+
+fn decode_value<'a>(bytes: &'a [u8]) -> Option<(&'a [u8], u64)> {
+    if bytes.len() < HEADER_LEN || bytes[0] != HEADER_TAG {
+        return None;
+    }
+    let expiry = decode_u64(bytes.slice(1, HEADER_LEN)).unwrap();
+    Some((bytes.slice_from(HEADER_LEN), expiry))
+}
+
+/// `bytes` without its expiry header, if it has one.
+pub fn strip_header(bytes: ~[u8]) -> ~[u8] {
+    if decode_value(bytes).is_some() {
+        bytes.slice_from(HEADER_LEN).to_owned()
+    } else {
+        bytes
+    }
+}
+
+fn is_expired(expiry: u64, now: u64) -> bool {
+    expiry != 0 && expiry <= now
+}
+
+/// Whether `key` is an entry of the expiry index.
+pub fn is_expiry_key(key: &[u8]) -> bool {
+    key.starts_with(INDEX_PREFIX)
+}
+
+fn index_key(expiry: u64, key: &[u8]) -> ~[u8] {
+    let mut index_key = INDEX_PREFIX.to_owned();
+    index_key.push_all(encode_u64(expiry));
+    index_key.push_all(key);
+    index_key
+}
+
+impl DB {
+    /// A view of this database whose values may expire
+    pub fn ttl<'r>(&'r self) -> Ttl<'r> {
+        Ttl {
+            db: self
+        }
+    }
+}
+
+impl<'r> Ttl<'r> {
+    /// Store `value` under `key` without an expiry time.
+    pub fn put(&self, key: &[u8], value: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        self.put_with_expiry(key, value, 0, options)
+    }
+
+    /// Store `value` under `key`, expiring `ttl_secs` seconds from now.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl_secs: u64,
+                        options: &[WriteOption]) -> Result<(), error> {
+        self.put_with_expiry(key, value, now() + ttl_secs, options)
+    }
+
+    fn put_with_expiry(&self, key: &[u8], value: &[u8], expiry: u64,
+                       options: &[WriteOption]) -> Result<(), error> {
+        let _guard = match self.db.key_locks.lock_within([key], LOCK_TIMEOUT_MS) {
+            Ok(guard) => guard,
+            Err(err) => return Err(err)
+        };
+        let mut batch = WriteBatch::new();
+        match self.remove_index_entry(&mut batch, key) {
+            Ok(_) => {},
+            Err(err) => return Err(err)
+        }
+        batch.put(key, encode_value(value, expiry));
+        if expiry != 0 {
+            batch.put(index_key(expiry, key), []);
+        }
+        self.db.write_ttl_values(&batch, options)
+    }
+
+    // Add the deletion of the index entry of the current value of `key`,
+    // if it has one, to `batch`.
+    fn remove_index_entry(&self, batch: &mut WriteBatch, key: &[u8]) -> Result<(), error> {
+        match self.db.get_opt(key, []) {
+            Ok(Some(bytes)) => match decode_value(bytes) {
+                Some((_, expiry)) if expiry != 0 => batch.delete(index_key(expiry, key)),
+                _ => {}
+            },
+            Ok(None) => {},
+            Err(err) => return Err(err)
+        }
+        Ok(())
+    }
+
+    /// The value of `key`, or `None` if it is missing or expired.
+    pub fn get(&self, key: &[u8], options: &[ReadOption]) -> Result<Option<~[u8]>, error> {
+        match self.db.get_opt(key, options) {
+            Ok(Some(bytes)) => match decode_value(bytes) {
+                Some((value, expiry)) if !is_expired(expiry, now()) => Ok(Some(value.to_owned())),
+                _ => Ok(None)
+            },
+            Ok(None) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn delete(&self, key: &[u8], options: &[WriteOption]) -> Result<(), error> {
+        let _guard = match self.db.key_locks.lock_within([key], LOCK_TIMEOUT_MS) {
+            Ok(guard) => guard,
+            Err(err) => return Err(err)
+        };
+        let mut batch = WriteBatch::new();
+        match self.remove_index_entry(&mut batch, key) {
+            Ok(_) => {},
+            Err(err) => return Err(err)
+        }
+        batch.delete(key);
+        self.db.write(&batch, options)
+    }
+
+    /// Iterate over the entries that have not expired, skipping the
+    /// expiry index.
+    pub fn iter(&self, options: &[ReadOption]) -> TtlIterator {
+        TtlIterator {
+            iter: self.db.iter(options),
+            now: now()
+        }
+    }
+
+    /// Delete expired keys found among the next `limit` entries of the
+    /// expiry index, in one batch. Returns how many keys were deleted.
+    pub fn sweep(&self, limit: uint) -> Result<uint, error> {
+        match sweep_batch(self.db, now(), limit) {
+            Ok((_, deleted)) => Ok(deleted),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Start a task that sweeps every `interval_ms` milliseconds, in
+    /// batches of `batch_size` index entries. The task writes through a
+    /// handle sharing this one's locks, indexes, merge operator and change
+    /// log, so its deletes are the same as this handle's.
+    pub fn spawn_sweeper(&self, interval_ms: u64, batch_size: uint) -> Sweeper<'r> {
+        let handle = self.db.shared_handle();
+        let (stop_port, stop_chan) = Chan::new();
+        let (done_port, done_chan) = Chan::new();
+        task::spawn(proc() {
+            let db = handle.open();
+            loop {
+                match stop_port.try_recv() {
+                    Some(()) => break,
+                    None => {}
+                }
+                // Keep sweeping while full batches come back; with a
+                // batch size of 0 every batch is both full and empty.
+                loop {
+                    match sweep_batch(&db, now(), batch_size) {
+                        Ok((n, _)) if n > 0 && n == batch_size => {},
+                        _ => break
+                    }
+                }
+                timer::sleep(interval_ms);
+            }
+            done_chan.send(());
+        });
+        Sweeper {
+            db: self.db,
+            stop: stop_chan,
+            done: done_port
+        }
+    }
+}
+
+// Process up to `limit` index entries older than `now` in one batch,
+// deleting the keys they point to. Returns the number of entries
+// processed and of keys deleted.
+fn sweep_batch(db: &DB, now: u64, limit: uint) -> Result<(uint, uint), error> {
+    let end = index_key(now + 1, []);
+    let mut entries = ~[];
+    let mut it = db.iter([]);
+    it.seek(INDEX_PREFIX);
+    while entries.len() < limit && it.is_valid() {
+        let entry = it.key();
+        if entry >= end {
+            break;
+        }
+        let expiry = decode_u64(entry.slice(INDEX_PREFIX.len(), INDEX_PREFIX.len() + 8)).unwrap();
+        let key = entry.slice_from(INDEX_PREFIX.len() + 8).to_owned();
+        entries.push((entry, key, expiry));
+        it.next();
+    }
+    let err = it.get_error();
+    unsafe {
+        leveldb_iter_destroy(it.iter);
+    }
+    match err {
+        Some(err) => return Err(err),
+        None => {}
+    }
+    if entries.is_empty() {
+        return Ok((0, 0));
+    }
+
+    let keys: ~[&[u8]] = entries.iter().map(|&(_, ref key, _)| key.as_slice()).collect();
+    let _guard = match db.key_locks.lock_within(keys, LOCK_TIMEOUT_MS) {
+        Ok(guard) => guard,
+        Err(err) => return Err(err)
+    };
+    let mut batch = WriteBatch::new();
+    let mut deleted = 0u;
+    for &(ref entry, ref key, expiry) in entries.iter() {
+        batch.delete(*entry);
+        // The key may have been rewritten since its entry was read.
+        match db.get_opt(*key, []) {
+            Ok(Some(bytes)) => match decode_value(bytes) {
+                Some((_, current)) if current == expiry => {
+                    batch.delete(*key);
+                    deleted += 1;
+                },
+                _ => {}
+            },
+            Ok(None) => {},
+            Err(err) => return Err(err)
+        }
+    }
+    match db.write(&batch, []) {
+        Ok(_) => Ok((entries.len(), deleted)),
+        Err(err) => Err(err)
+    }
+}
+
+#[unsafe_destructor]
+impl<'r> Drop for Sweeper<'r> {
+    fn drop(&mut self) {
+        self.stop.send(());
+        self.done.recv();
+    }
+}
+
+/// Iterator over the entries of a `Ttl` view that have not expired
+pub struct TtlIterator {
+    priv iter: DBIterator,
+    priv now: u64
+}
+
+impl Iterator<(~[u8], ~[u8])> for TtlIterator {
+    fn next(&mut self) -> Option<(~[u8], ~[u8])> {
+        loop {
+            let (key, bytes) = match self.iter.next() {
+                Some(pair) => pair,
+                None => return None
+            };
+            if key.starts_with(INDEX_PREFIX) {
+                self.iter.seek(INDEX_END);
+                continue;
+            }
+            match decode_value(bytes) {
+                Some((value, expiry)) if !is_expired(expiry, self.now) => {
+                    return Some((key, value.to_owned()));
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+impl TtlIterator {
+    pub fn seek(&mut self, key: &[u8]) {
+        self.iter.seek(key);
+    }
+
+    pub fn get_error(&self) -> Option<error> {
+        self.iter.get_error()
+    }
+}
@@ -0,0 +1,171 @@
+//! Integrity verification.
+//!
+//! `verify` reads every entry with checksum verification on and without
+//! filling the block cache. A LevelDB iterator that meets a corrupt block
+//! records the error and carries on with the next block, and the error
+//! then stays set, so the scan checks for it after every step. On an error
+//! it seeks fresh iterators to a series of increasingly distant keys past
+//! the last key read before the error appeared until one reads cleanly,
+//! and reports the keys in between as a corrupt range. The range may
+//! therefore cover healthy entries near the damage.
+
+use std::cmp;
+
+use super::{DB, error};
+use super::cleveldb::*;
+use super::options::VERIFY_CHECKSUM;
+
+/// Keys that could not be read
+#[deriving(Eq, Clone)]
+pub struct CorruptRange {
+    /// The last key read before the corruption, if any
+    after: Option<~[u8]>,
+    /// The first key read past it, if the scan recovered
+    before: Option<~[u8]>,
+    error: error
+}
+
+#[deriving(Eq, Clone)]
+pub struct VerifyReport {
+    entries: u64,
+    /// Key and value bytes read
+    bytes: u64,
+    corrupt: ~[CorruptRange]
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+// Keys to resume from after an error following `last`, in increasing
+// order: successors of ever shorter prefixes of `last`, then of each
+// first byte.
+fn probes(last: &[u8]) -> ~[~[u8]] {
+    let mut candidates = ~[];
+    let mut n = last.len();
+    while n > 0 {
+        if last[n - 1] < 0xff {
+            let mut probe = last.slice_to(n - 1).to_owned();
+            probe.push(last[n - 1] + 1);
+            candidates.push(probe);
+        }
+        n -= 1;
+    }
+    let first = if last.is_empty() { 1 } else { last[0] as uint + 1 };
+    for b in range(cmp::max(first, 1), 256) {
+        candidates.push(~[b as u8]);
+    }
+    let mut probes: ~[~[u8]] = ~[];
+    for probe in candidates.move_iter() {
+        let increasing = match probes.last() {
+            Some(prev) => probe > *prev,
+            None => probe.as_slice() > last
+        };
+        if increasing {
+            probes.push(probe);
+        }
+    }
+    probes
+}
+
+impl DB {
+    /// Read every entry in `[start, end)`, or every entry, verifying
+    /// checksums, and report what could not be read.
+    pub fn verify(&self, range: Option<(&[u8], &[u8])>) -> VerifyReport {
+        let c_options = unsafe {
+            let c_options = leveldb_readoptions_create();
+            leveldb_readoptions_set_verify_checksums(c_options, 1u8);
+            leveldb_readoptions_set_fill_cache(c_options, 0u8);
+            c_options as *leveldb_readoptions_t
+        };
+        let end = range.map(|(_, end)| end.to_owned());
+        let is_past_end = |key: &[u8]| match end {
+            Some(ref end) => key >= end.as_slice(),
+            None => false
+        };
+        let mut report = VerifyReport {
+            entries: 0,
+            bytes: 0,
+            corrupt: ~[]
+        };
+        let mut it = self.iter_with(c_options);
+        match range {
+            Some((start, _)) => it.seek(start),
+            None => {}
+        }
+        // The last key read while the iterator had no error.
+        let mut last: Option<~[u8]> = None;
+        loop {
+            let err = match it.get_error() {
+                Some(err) => err,
+                None => match it.next() {
+                    Some((key, value)) => {
+                        if is_past_end(key) {
+                            break;
+                        }
+                        report.entries += 1;
+                        report.bytes += (key.len() + value.len()) as u64;
+                        last = Some(key);
+                        continue;
+                    },
+                    None => break
+                }
+            };
+            let base = match last {
+                Some(ref key) => key.clone(),
+                None => match range {
+                    Some((start, _)) => start.to_owned(),
+                    None => ~[]
+                }
+            };
+            let mut resumed = None;
+            for probe in probes(base).move_iter() {
+                if is_past_end(probe) {
+                    break;
+                }
+                let mut probe_it = self.iter_with(c_options);
+                probe_it.seek(probe);
+                if probe_it.get_error().is_none() {
+                    resumed = Some(probe_it);
+                    break;
+                }
+                unsafe {
+                    leveldb_iter_destroy(probe_it.iter);
+                }
+            }
+            let before = match resumed {
+                Some(ref probe_it) if probe_it.is_valid() && !is_past_end(probe_it.key()) => {
+                    Some(probe_it.key())
+                },
+                _ => None
+            };
+            report.corrupt.push(CorruptRange {
+                after: last.clone(),
+                before: before.clone(),
+                error: err
+            });
+            match (resumed, before) {
+                (Some(probe_it), Some(_)) => {
+                    unsafe {
+                        leveldb_iter_destroy(it.iter);
+                    }
+                    it = probe_it;
+                },
+                (Some(probe_it), None) => {
+                    unsafe {
+                        leveldb_iter_destroy(probe_it.iter);
+                    }
+                    break;
+                },
+                (None, _) => break
+            }
+        }
+        unsafe {
+            leveldb_iter_destroy(it.iter);
+            leveldb_readoptions_destroy(c_options as *mut leveldb_readoptions_t);
+        }
+        report
+    }
+}